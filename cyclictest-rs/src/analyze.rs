@@ -0,0 +1,391 @@
+//! Offline analysis of a file of --record
+//!
+//! Everything but the run totals is recomputed from the samples, so the
+//! histogram can have other buckets than the run had. When the ring buffers
+//! overwrote samples, only the last samples of each thread are analyzed.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+
+use crate::{Clock, Recording, Sample};
+
+const PERCENTILES: [f64; 5] = [50.0, 90.0, 99.0, 99.9, 99.99];
+/// Percentile of all samples that is the default spike threshold
+const SPIKE_PERCENTILE: f64 = 99.9;
+
+/// Parameters of the analysis, see the analyze subcommand
+#[derive(Clone, Debug)]
+pub struct AnalyzeOptions {
+    /// Number of histogram buckets
+    pub hist_size: usize,
+    /// Width of a histogram bucket in ns
+    pub bucket_ns: u64,
+    /// Label the histogram rows in ns instead of µs
+    pub nsecs: bool,
+    /// Samples above this latency are spikes, None for the 99.9th percentile
+    /// of all samples
+    pub threshold_ns: Option<u64>,
+    /// Number of the largest spikes in the timeline
+    pub spikes: usize,
+    /// Spikes of different threads within this time are correlated
+    pub window_ns: u64,
+}
+
+/// A sample above the spike threshold
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Spike {
+    thread: usize,
+    sample: Sample,
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    //! The nearest-rank percentile of sorted latencies, 0 without any
+    // Without the epsilon 99.9% of 1000 samples would be rank 1000
+    match sorted.len() {
+        0 => 0,
+        n => sorted[((p / 100.0 * n as f64 - 1e-9).ceil() as usize).clamp(1, n) - 1],
+    }
+}
+
+fn sorted_latencies<'a>(samples: impl Iterator<Item = &'a Sample>) -> Vec<u64> {
+    let mut latencies: Vec<u64> = samples.map(|s| s.latency_ns).collect();
+    latencies.sort_unstable();
+    latencies
+}
+
+fn histogram(samples: &[Sample], hist_size: usize, bucket_ns: u64) -> (Vec<u32>, u32) {
+    //! The buckets and overflows like the run accounts them
+    let mut hist = vec![0; hist_size];
+    let mut overflows = 0;
+    for sample in samples {
+        match hist.get_mut((sample.latency_ns / bucket_ns) as usize) {
+            Some(count) => *count += 1,
+            None => overflows += 1,
+        }
+    }
+    (hist, overflows)
+}
+
+fn spikes(recording: &Recording, threshold_ns: u64) -> Vec<Spike> {
+    //! The samples of all threads above the threshold, in time order
+    let mut spikes: Vec<Spike> = recording
+        .threads
+        .iter()
+        .enumerate()
+        .flat_map(|(thread, t)| {
+            t.samples
+                .iter()
+                .filter(|s| s.latency_ns > threshold_ns)
+                .map(move |&sample| Spike { thread, sample })
+        })
+        .collect();
+    spikes.sort_by_key(|s| s.sample.wakeup_ns);
+    spikes
+}
+
+fn neighbours(spikes: &[Spike], i: usize, window_ns: u64) -> Vec<usize> {
+    //! The other threads with a spike within the window around spike i
+    let t = spikes[i].sample.wakeup_ns;
+    let before = spikes[..i]
+        .iter()
+        .rev()
+        .take_while(|s| t - s.sample.wakeup_ns <= window_ns);
+    let after = spikes[i + 1..]
+        .iter()
+        .take_while(|s| s.sample.wakeup_ns - t <= window_ns);
+    let mut threads: Vec<usize> = before
+        .chain(after)
+        .map(|s| s.thread)
+        .filter(|&thread| thread != spikes[i].thread)
+        .collect();
+    threads.sort_unstable();
+    threads.dedup();
+    threads
+}
+
+fn correlation(spikes: &[Spike], num_threads: usize, window_ns: u64) -> Vec<Vec<u64>> {
+    //! Row a, column b: the spikes of thread a with a spike of thread b
+    //! within the window, the diagonal has all spikes of thread a
+    let mut matrix = vec![vec![0; num_threads]; num_threads];
+    for (i, spike) in spikes.iter().enumerate() {
+        matrix[spike.thread][spike.thread] += 1;
+        for other in neighbours(spikes, i, window_ns) {
+            matrix[spike.thread][other] += 1;
+        }
+    }
+    matrix
+}
+
+fn us(ns: u64) -> f64 {
+    ns as f64 / 1000f64
+}
+
+fn cpu_name(cpu: Option<usize>) -> String {
+    cpu.map_or("-".to_string(), |cpu| cpu.to_string())
+}
+
+pub fn analyze(
+    w: &mut impl Write,
+    recording: &Recording,
+    options: &AnalyzeOptions,
+) -> io::Result<()> {
+    //! Print the histogram, percentiles, per-thread and per-CPU summaries,
+    //! the spike timeline and the correlation of the spikes of a recording
+    let threads = &recording.threads;
+    let clock = Clock::value_variants()
+        .iter()
+        .find(|c| c.clockid() == recording.clockid)
+        .map_or("unknown clock", |c| c.name());
+    let all = sorted_latencies(threads.iter().flat_map(|t| &t.samples));
+    writeln!(
+        w,
+        "Recording: {} threads, {} samples, {}",
+        threads.len(),
+        all.len(),
+        clock
+    )?;
+    if !recording.checks.is_empty() {
+        writeln!(w, "Checks of the host")?;
+        for check in &recording.checks {
+            writeln!(w, "{}", check)?;
+        }
+    }
+
+    let (unit_ns, unit) = match options.nsecs {
+        true => (1, "ns"),
+        false => (1_000, "us"),
+    };
+    let hists: Vec<(Vec<u32>, u32)> = threads
+        .iter()
+        .map(|t| histogram(&t.samples, options.hist_size, options.bucket_ns))
+        .collect();
+    writeln!(w, "Histogram: Rows:Latency_{}; Columns:Threads", unit)?;
+    for h in 0..options.hist_size {
+        write!(w, "{:2} ", h as u64 * options.bucket_ns / unit_ns)?;
+        for (hist, _) in &hists {
+            write!(w, "{:5} ", hist[h])?;
+        }
+        writeln!(w)?;
+    }
+    write!(w, "Ov ")?;
+    for (_, overflows) in &hists {
+        write!(w, "{:5} ", overflows)?;
+    }
+    writeln!(w)?;
+
+    writeln!(w, "Run totals (µs)")?;
+    for (i, t) in threads.iter().enumerate() {
+        let (min, avg) = match t.cycles {
+            0 => (0, 0),
+            cycles => (t.min, t.sum / cycles),
+        };
+        writeln!(
+            w,
+            "T{} CPU {:>3}  Interval {:6}: Cycles {:8}  Min {:6.1}  Avg {:6.1}  Max {:6.1}  Overruns {:6}",
+            i,
+            cpu_name(t.cpu),
+            t.interval_ns / 1_000,
+            t.cycles,
+            us(min),
+            us(avg),
+            us(t.max),
+            t.overruns
+        )?;
+    }
+
+    writeln!(w, "Percentiles of the samples (µs)")?;
+    let write_percentiles = |w: &mut dyn Write, name: String, sorted: &[u64]| {
+        write!(w, "{}: Samples {:8}", name, sorted.len())?;
+        for p in PERCENTILES {
+            write!(w, "  P{} {:6.1}", p, us(percentile(sorted, p)))?;
+        }
+        writeln!(w, "  Max {:6.1}", us(sorted.last().copied().unwrap_or(0)))
+    };
+    for (i, t) in threads.iter().enumerate() {
+        let sorted = sorted_latencies(t.samples.iter());
+        write_percentiles(w, format!("T{}     ", i), &sorted)?;
+    }
+    let mut cpus: BTreeMap<Option<usize>, Vec<&Sample>> = BTreeMap::new();
+    for t in threads {
+        cpus.entry(t.cpu).or_default().extend(&t.samples);
+    }
+    for (cpu, samples) in &cpus {
+        let sorted = sorted_latencies(samples.iter().copied());
+        write_percentiles(w, format!("CPU {:>3}", cpu_name(*cpu)), &sorted)?;
+    }
+    write_percentiles(w, "All    ".to_string(), &all)?;
+
+    let threshold_ns = options
+        .threshold_ns
+        .unwrap_or_else(|| percentile(&all, SPIKE_PERCENTILE));
+    let spikes = spikes(recording, threshold_ns);
+    let mut largest: Vec<usize> = (0..spikes.len()).collect();
+    largest.sort_by_key(|&i| std::cmp::Reverse(spikes[i].sample.latency_ns));
+    largest.truncate(options.spikes);
+    largest.sort_unstable();
+    writeln!(
+        w,
+        "Spikes over {:.1} µs: {}, the largest {} in time order",
+        us(threshold_ns),
+        spikes.len(),
+        largest.len()
+    )?;
+    let start_ns = threads
+        .iter()
+        .filter_map(|t| t.samples.first())
+        .map(|s| s.wakeup_ns)
+        .min()
+        .unwrap_or(0);
+    for i in largest {
+        let spike = &spikes[i];
+        write!(
+            w,
+            "{:+14.6} s T{} cycle {}: {:.1} µs",
+            (spike.sample.wakeup_ns - start_ns) as f64 / 1e9,
+            spike.thread,
+            spike.sample.cycle,
+            us(spike.sample.latency_ns)
+        )?;
+        let others = neighbours(&spikes, i, options.window_ns);
+        if !others.is_empty() {
+            let others: Vec<String> = others.iter().map(|t| format!("T{}", t)).collect();
+            write!(w, "  with {}", others.join(", "))?;
+        }
+        writeln!(w)?;
+    }
+
+    writeln!(
+        w,
+        "Correlation: Rows:Spikes of a thread; Columns:With a spike of the thread within {:.1} µs",
+        us(options.window_ns)
+    )?;
+    write!(w, "   ")?;
+    for i in 0..threads.len() {
+        write!(w, "{:>5} ", format!("T{}", i))?;
+    }
+    writeln!(w)?;
+    for (i, row) in correlation(&spikes, threads.len(), options.window_ns)
+        .iter()
+        .enumerate()
+    {
+        write!(w, "{:<3}", format!("T{}", i))?;
+        for count in row {
+            write!(w, "{:5} ", count)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ThreadRecord;
+
+    fn sample(cycle: u64, wakeup_ns: u64, latency_ns: u64) -> Sample {
+        Sample {
+            cycle,
+            wakeup_ns,
+            latency_ns,
+        }
+    }
+
+    fn recording() -> Recording {
+        // T0 and T1 spike together at 10 ms, T1 alone at 30 ms
+        let thread = |cpu, latencies: &[(u64, u64)]| ThreadRecord {
+            cpu,
+            interval_ns: 1_000_000,
+            cycles: latencies.len() as u64,
+            samples: latencies
+                .iter()
+                .enumerate()
+                .map(|(i, &(wakeup, latency))| sample(i as u64 + 1, wakeup, latency))
+                .collect(),
+            ..Default::default()
+        };
+        Recording {
+            clockid: libc::CLOCK_MONOTONIC,
+            checks: vec![],
+            threads: vec![
+                thread(
+                    Some(0),
+                    &[(0, 1_000), (10_000_000, 50_000), (20_000_000, 2_000)],
+                ),
+                thread(
+                    Some(0),
+                    &[
+                        (10_020_000, 40_000),
+                        (20_030_000, 3_000),
+                        (30_000_000, 60_000),
+                    ],
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_percentile() {
+        let sorted: Vec<u64> = (1..=1000).collect();
+        assert_eq!(percentile(&sorted, 50.0), 500);
+        assert_eq!(percentile(&sorted, 99.9), 999);
+        assert_eq!(percentile(&sorted, 99.99), 1000);
+        assert_eq!(percentile(&sorted, 0.0), 1);
+        assert_eq!(percentile(&[], 99.0), 0);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let samples = [sample(1, 0, 500), sample(2, 0, 1_500), sample(3, 0, 9_000)];
+        assert_eq!(histogram(&samples, 2, 1_000), (vec![1, 1], 1));
+        assert_eq!(
+            histogram(&samples, 10, 1_000),
+            (vec![1, 1, 0, 0, 0, 0, 0, 0, 0, 1], 0)
+        );
+    }
+
+    #[test]
+    fn test_spike_correlation() {
+        let recording = recording();
+        let spikes = spikes(&recording, 10_000);
+        let threads: Vec<usize> = spikes.iter().map(|s| s.thread).collect();
+        assert_eq!(threads, vec![0, 1, 1]);
+        assert_eq!(neighbours(&spikes, 0, 100_000), vec![1]);
+        assert_eq!(neighbours(&spikes, 0, 10_000), vec![]);
+        assert_eq!(neighbours(&spikes, 2, 100_000), vec![]);
+        assert_eq!(
+            correlation(&spikes, 2, 100_000),
+            vec![vec![1, 1], vec![1, 2]]
+        );
+    }
+
+    #[test]
+    fn test_analyze() {
+        let options = AnalyzeOptions {
+            hist_size: 10,
+            bucket_ns: 10_000,
+            nsecs: false,
+            threshold_ns: None,
+            spikes: 20,
+            window_ns: 100_000,
+        };
+        let mut out = vec![];
+        analyze(&mut out, &recording(), &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("Recording: 2 threads, 6 samples, CLOCK_MONOTONIC\n"));
+        // The 99.9th percentile of 6 samples is the largest, no spikes
+        assert!(out.contains("Spikes over 60.0 µs: 0, the largest 0 in time order"));
+
+        let options = AnalyzeOptions {
+            threshold_ns: Some(10_000),
+            ..options
+        };
+        let mut out = vec![];
+        analyze(&mut out, &recording(), &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("     +0.010000 s T0 cycle 2: 50.0 µs  with T1\n"));
+        assert!(out.contains("     +0.030000 s T1 cycle 3: 60.0 µs\n"));
+        assert!(out.contains("CPU   0: Samples        6"));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::*;
 use std::error::Error;
+use std::sync::Barrier;
 
 pub fn run_benchmarks() -> Result<(), Box<dyn Error>> {
     //! Run some experimental benchmarks
@@ -16,6 +17,10 @@ pub fn run_benchmarks() -> Result<(), Box<dyn Error>> {
     benchmark_large_box(10)?;
     benchmark_large_box(100)?;
 
+    benchmark_stats(1, 100_000)?;
+    benchmark_stats(4, 100_000)?;
+    benchmark_stats(12, 100_000)?;
+
     Ok(())
 }
 
@@ -27,11 +32,11 @@ fn benchmark_push(samples: u32) -> Result<(), Box<dyn Error>> {
     let mut vec: Vec<i32> = vec![0];
 
     for _s in 0..samples {
-        let start = clock_gettime();
+        let start = clock_gettime(libc::CLOCK_MONOTONIC);
 
         vec.push(42);
 
-        let end = clock_gettime();
+        let end = clock_gettime(libc::CLOCK_MONOTONIC);
         diff = Timespec::diff_ns(start, end);
 
         accumulator += diff as u64;
@@ -61,11 +66,11 @@ fn benchmark_small_box(samples: u32) -> Result<(), Box<dyn Error>> {
     let mut max_diff: i64 = 0;
 
     for _s in 0..samples {
-        let start = clock_gettime();
+        let start = clock_gettime(libc::CLOCK_MONOTONIC);
 
         let mybox = Box::new(88); // Just a simple box for now
 
-        let end = clock_gettime();
+        let end = clock_gettime(libc::CLOCK_MONOTONIC);
         diff = Timespec::diff_ns(start, end);
 
         let _ = *mybox;
@@ -97,11 +102,11 @@ fn benchmark_large_box(samples: u32) -> Result<(), Box<dyn Error>> {
     let mut boxes: Vec<Box<Vec<i32>>> = vec![];
 
     for _s in 0..samples {
-        let start = clock_gettime();
+        let start = clock_gettime(libc::CLOCK_MONOTONIC);
 
         let mybox = Box::new(v.clone());
 
-        let end = clock_gettime();
+        let end = clock_gettime(libc::CLOCK_MONOTONIC);
         diff = Timespec::diff_ns(start, end);
 
         let _ = *mybox;
@@ -122,3 +127,73 @@ fn benchmark_large_box(samples: u32) -> Result<(), Box<dyn Error>> {
     );
     Ok(())
 }
+
+fn benchmark_stats(num_threads: usize, samples: u32) -> Result<(), Box<dyn Error>> {
+    //! Compare recording a latency behind one global Mutex, like the
+    //! measurement threads did before, with the lock-free per-thread stats
+    println!(
+        "Running stats benchmark with {} threads and {} samples",
+        num_threads, samples
+    );
+    let locked = Arc::new(Mutex::new(Stats::new(num_threads, 15, 1_000)));
+    let (average, max) = run_stats_threads(num_threads, samples, move |thread, latency, cycle| {
+        locked.lock().unwrap().threads[thread].record(latency, cycle);
+    });
+    println!(
+        "Mutex:     Average Time {:?} µs Maximal {:?} µs",
+        average as f64 / 1000f64,
+        max as f64 / 1000f64
+    );
+
+    let shared = Arc::new(Stats::new(num_threads, 15, 1_000));
+    let (average, max) = run_stats_threads(num_threads, samples, move |thread, latency, cycle| {
+        shared.threads[thread].record(latency, cycle);
+    });
+    println!(
+        "Lock-free: Average Time {:?} µs Maximal {:?} µs",
+        average as f64 / 1000f64,
+        max as f64 / 1000f64
+    );
+    Ok(())
+}
+
+fn run_stats_threads<F>(num_threads: usize, samples: u32, record: F) -> (u64, u64)
+where
+    F: Fn(usize, u64, u64) + Send + Sync + 'static,
+{
+    //! Record samples from all threads at once, returns the average and
+    //! maximal time of a record call in ns
+    let record = Arc::new(record);
+    let barrier = Arc::new(Barrier::new(num_threads));
+    let handles: Vec<_> = (0..num_threads)
+        .map(|thread| {
+            let record = Arc::clone(&record);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                let mut accumulator: u64 = 0;
+                let mut max_diff: i64 = 0;
+                barrier.wait();
+                for s in 0..samples {
+                    let start = clock_gettime(libc::CLOCK_MONOTONIC);
+
+                    record(thread, (s % 20) as u64 * 1_000, s as u64);
+
+                    let end = clock_gettime(libc::CLOCK_MONOTONIC);
+                    let diff = Timespec::diff_ns(start, end);
+                    accumulator += diff as u64;
+                    max_diff = max_diff.max(diff);
+                }
+                (accumulator, max_diff as u64)
+            })
+        })
+        .collect();
+
+    let mut accumulator: u64 = 0;
+    let mut max: u64 = 0;
+    for handle in handles {
+        let (sum, thread_max) = handle.join().unwrap();
+        accumulator += sum;
+        max = max.max(thread_max);
+    }
+    (accumulator / (num_threads as u64 * samples as u64), max)
+}
@@ -0,0 +1,396 @@
+//! Preflight check of the system settings that spoil real-time latencies
+//!
+//! Everything is read from /proc and /sys, the kernel version string in
+//! /proc/sys/kernel/version is the one that uname -v prints. The checks never
+//! change a setting, they only explain what to change.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but latencies will likely be worse than possible
+    Warn,
+    /// Real-time latencies cannot be expected
+    Fail,
+}
+
+impl CheckStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Fail => "fail",
+        }
+    }
+}
+
+/// Result of one check
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    /// The setting as found on the system
+    pub value: String,
+    pub explanation: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: CheckStatus, value: &str, explanation: &str) -> Check {
+        Check {
+            name: name.to_string(),
+            status,
+            value: value.to_string(),
+            explanation: explanation.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:4}  {:14} {:24} {}",
+            self.status.name().to_uppercase(),
+            self.name,
+            self.value,
+            self.explanation
+        )
+    }
+}
+
+/// Reads the files below a root directory, / except in the tests
+struct Host {
+    root: PathBuf,
+}
+
+impl Host {
+    fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    fn read(&self, path: &str) -> Option<String> {
+        //! Trimmed content of a file, None if it cannot be read
+        fs::read_to_string(self.path(path))
+            .ok()
+            .map(|s| s.trim().to_string())
+    }
+}
+
+pub fn run_checks() -> Vec<Check> {
+    //! Check the running system
+    checks(Path::new("/"))
+}
+
+fn checks(root: &Path) -> Vec<Check> {
+    let host = Host {
+        root: root.to_path_buf(),
+    };
+    let cmdline = host.read("/proc/cmdline").unwrap_or_default();
+    vec![
+        check_realtime(&host),
+        check_rt_throttling(&host),
+        check_cmdline(
+            &cmdline,
+            "isolcpus",
+            "keeps the scheduler from putting other tasks on the measurement CPUs",
+        ),
+        check_cmdline(
+            &cmdline,
+            "nohz_full",
+            "stops the scheduler tick on CPUs with a single task",
+        ),
+        check_cmdline(
+            &cmdline,
+            "rcu_nocbs",
+            "moves RCU callbacks off the measurement CPUs",
+        ),
+        check_governors(&host),
+        check_smt(&host),
+        check_irqbalance(&host),
+        check_clocksource(&host),
+    ]
+}
+
+fn check_realtime(host: &Host) -> Check {
+    let version = host.read("/proc/sys/kernel/version").unwrap_or_default();
+    if host.read("/sys/kernel/realtime").as_deref() == Some("1") {
+        Check::new(
+            "realtime",
+            CheckStatus::Pass,
+            "PREEMPT_RT",
+            "/sys/kernel/realtime is 1",
+        )
+    } else if version.contains("PREEMPT_RT") {
+        Check::new(
+            "realtime",
+            CheckStatus::Pass,
+            "PREEMPT_RT",
+            "uname -v reports PREEMPT_RT",
+        )
+    } else if version.contains("PREEMPT") {
+        Check::new(
+            "realtime",
+            CheckStatus::Warn,
+            "PREEMPT",
+            "preemptible kernel without PREEMPT_RT, expect latencies of several 100 µs",
+        )
+    } else {
+        Check::new(
+            "realtime",
+            CheckStatus::Fail,
+            "none",
+            "no PREEMPT_RT kernel, boot a kernel with CONFIG_PREEMPT_RT",
+        )
+    }
+}
+
+fn check_rt_throttling(host: &Host) -> Check {
+    let name = "rt_throttling";
+    let period = host.read("/proc/sys/kernel/sched_rt_period_us");
+    match host.read("/proc/sys/kernel/sched_rt_runtime_us") {
+        Some(runtime) if runtime == "-1" => {
+            Check::new(name, CheckStatus::Pass, "off", "sched_rt_runtime_us is -1")
+        }
+        Some(runtime) => Check {
+            name: name.to_string(),
+            status: CheckStatus::Warn,
+            value: format!("{}/{} µs", runtime, period.as_deref().unwrap_or("?")),
+            explanation: format!(
+                "RT threads are stopped after {} µs per period, \
+                 write -1 to /proc/sys/kernel/sched_rt_runtime_us",
+                runtime
+            ),
+        },
+        None => Check::new(
+            name,
+            CheckStatus::Warn,
+            "unknown",
+            "cannot read /proc/sys/kernel/sched_rt_runtime_us",
+        ),
+    }
+}
+
+fn cmdline_value<'a>(cmdline: &'a str, param: &str) -> Option<&'a str> {
+    //! Value of a kernel parameter, empty for a parameter without value
+    cmdline
+        .split_whitespace()
+        .find_map(|arg| match arg.split_once('=') {
+            Some((key, value)) if key == param => Some(value),
+            None if arg == param => Some(""),
+            _ => None,
+        })
+}
+
+fn check_cmdline(cmdline: &str, param: &'static str, purpose: &str) -> Check {
+    match cmdline_value(cmdline, param) {
+        Some(value) => Check::new(param, CheckStatus::Pass, value, purpose),
+        None => Check {
+            name: param.to_string(),
+            status: CheckStatus::Warn,
+            value: "not set".to_string(),
+            explanation: format!("{}={{CPUS}} on the kernel command line {}", param, purpose),
+        },
+    }
+}
+
+fn check_governors(host: &Host) -> Check {
+    let name = "cpu_governor";
+    let mut governors: Vec<String> = fs::read_dir(host.path("/sys/devices/system/cpu"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            file_name
+                .strip_prefix("cpu")
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .filter_map(|entry| fs::read_to_string(entry.path().join("cpufreq/scaling_governor")).ok())
+        .map(|governor| governor.trim().to_string())
+        .collect();
+    governors.sort();
+    governors.dedup();
+    match governors.as_slice() {
+        [] => Check::new(name, CheckStatus::Pass, "none", "no frequency scaling"),
+        [governor] if governor == "performance" => Check::new(
+            name,
+            CheckStatus::Pass,
+            governor,
+            "all CPUs run at full speed",
+        ),
+        _ => Check::new(
+            name,
+            CheckStatus::Warn,
+            &governors.join(","),
+            "frequency changes add latency, set the scaling_governor of all CPUs to performance",
+        ),
+    }
+}
+
+fn check_smt(host: &Host) -> Check {
+    let name = "smt";
+    match host.read("/sys/devices/system/cpu/smt/active").as_deref() {
+        Some("1") => Check::new(
+            name,
+            CheckStatus::Warn,
+            "on",
+            "sibling threads share a core, use nosmt or write off to /sys/devices/system/cpu/smt/control",
+        ),
+        Some(_) => Check::new(name, CheckStatus::Pass, "off", "one thread per core"),
+        None => Check::new(
+            name,
+            CheckStatus::Pass,
+            "not supported",
+            "one thread per core",
+        ),
+    }
+}
+
+fn check_irqbalance(host: &Host) -> Check {
+    let name = "irqbalance";
+    let running = fs::read_dir(host.path("/proc"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .bytes()
+                .all(|b| b.is_ascii_digit())
+        })
+        .any(|entry| {
+            fs::read_to_string(entry.path().join("comm"))
+                .is_ok_and(|comm| comm.trim() == "irqbalance")
+        });
+    match running {
+        true => Check::new(
+            name,
+            CheckStatus::Warn,
+            "running",
+            "moves interrupts onto the measurement CPUs, stop it or use IRQBALANCE_BANNED_CPULIST",
+        ),
+        false => Check::new(
+            name,
+            CheckStatus::Pass,
+            "not running",
+            "interrupts stay where they are placed",
+        ),
+    }
+}
+
+fn check_clocksource(host: &Host) -> Check {
+    let name = "clocksource";
+    let path = "/sys/devices/system/clocksource/clocksource0/current_clocksource";
+    match host.read(path) {
+        Some(clock) if clock == "tsc" || clock == "arch_sys_counter" => Check::new(
+            name,
+            CheckStatus::Pass,
+            &clock,
+            "clock_gettime is served from the vDSO",
+        ),
+        Some(clock) if clock == "jiffies" => Check::new(
+            name,
+            CheckStatus::Fail,
+            &clock,
+            "timer resolution is one tick, no high resolution timers",
+        ),
+        Some(clock) => Check::new(
+            name,
+            CheckStatus::Warn,
+            &clock,
+            "reading the clock needs a system call or slow hardware access",
+        ),
+        None => Check::new(
+            name,
+            CheckStatus::Warn,
+            "unknown",
+            "cannot read the clocksource",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_cmdline_value() {
+        let cmdline = "BOOT_IMAGE=/vmlinuz isolcpus=domain,2-3 quiet nohz_full=2-3";
+        assert_eq!(cmdline_value(cmdline, "isolcpus"), Some("domain,2-3"));
+        assert_eq!(cmdline_value(cmdline, "quiet"), Some(""));
+        assert_eq!(cmdline_value(cmdline, "rcu_nocbs"), None);
+    }
+
+    #[test]
+    fn test_checks() {
+        let root = std::env::temp_dir().join(format!("cyclictest-rs-check-{}", std::process::id()));
+        write(&root, "sys/kernel/realtime", "1\n");
+        write(&root, "proc/sys/kernel/sched_rt_runtime_us", "950000\n");
+        write(&root, "proc/sys/kernel/sched_rt_period_us", "1000000\n");
+        write(&root, "proc/cmdline", "isolcpus=2-3 rcu_nocbs=2-3\n");
+        write(
+            &root,
+            "sys/devices/system/cpu/cpu0/cpufreq/scaling_governor",
+            "performance\n",
+        );
+        write(
+            &root,
+            "sys/devices/system/cpu/cpu1/cpufreq/scaling_governor",
+            "powersave\n",
+        );
+        write(&root, "sys/devices/system/cpu/smt/active", "0\n");
+        write(&root, "proc/42/comm", "irqbalance\n");
+        write(
+            &root,
+            "sys/devices/system/clocksource/clocksource0/current_clocksource",
+            "hpet\n",
+        );
+
+        let checks = checks(&root);
+        fs::remove_dir_all(&root).unwrap();
+        let status = |name| checks.iter().find(|c| c.name == name).unwrap().status;
+        assert_eq!(status("realtime"), CheckStatus::Pass);
+        assert_eq!(status("rt_throttling"), CheckStatus::Warn);
+        assert_eq!(status("isolcpus"), CheckStatus::Pass);
+        assert_eq!(status("nohz_full"), CheckStatus::Warn);
+        assert_eq!(status("rcu_nocbs"), CheckStatus::Pass);
+        assert_eq!(status("cpu_governor"), CheckStatus::Warn);
+        assert_eq!(status("smt"), CheckStatus::Pass);
+        assert_eq!(status("irqbalance"), CheckStatus::Warn);
+        assert_eq!(status("clocksource"), CheckStatus::Warn);
+        assert_eq!(checks[1].value, "950000/1000000 µs");
+        assert_eq!(checks[5].value, "performance,powersave");
+    }
+
+    #[test]
+    fn test_realtime_from_version() {
+        let root = std::env::temp_dir().join(format!("cyclictest-rs-rt-{}", std::process::id()));
+        write(
+            &root,
+            "proc/sys/kernel/version",
+            "#1 SMP PREEMPT_DYNAMIC Debian\n",
+        );
+        let host = Host { root: root.clone() };
+        assert_eq!(check_realtime(&host).status, CheckStatus::Warn);
+        write(&root, "proc/sys/kernel/version", "#1 SMP Debian\n");
+        assert_eq!(check_realtime(&host).status, CheckStatus::Fail);
+        write(
+            &root,
+            "proc/sys/kernel/version",
+            "#1 SMP PREEMPT_RT Debian\n",
+        );
+        assert_eq!(check_realtime(&host).status, CheckStatus::Pass);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+//! Configuration of a measurement run for embedding the library
+//!
+//! The command line builds the same configuration, so the defaults are the
+//! defaults of the command line options:
+//!
+//!     use cyclictest_rs::{MeasurementConfig, MeasurementType, Policy};
+//!
+//!     let config = MeasurementConfig::new(MeasurementType::ClockNanosleepGettime)
+//!         .threads(2)
+//!         .interval_us(200)
+//!         .loops(1_000)
+//!         .policy(Policy::Other)
+//!         .priority(0);
+//!     // let report = cyclictest_rs::run(&config)?;
+
+use std::time::Duration;
+
+use crate::{Clock, DeadlineParam, MeasurementType, Policy, SleepBackend, TimerMode};
+
+/// Parameters of a measurement run, see the command line options
+#[derive(Clone, Debug)]
+pub struct MeasurementConfig {
+    pub(crate) measurement: MeasurementType,
+    pub(crate) num_threads: usize,
+    /// Base interval of the first thread in µs
+    pub(crate) interval_us: u32,
+    /// Each further thread gets an interval that is longer by this in µs
+    pub(crate) distance_us: u32,
+    /// Number of cycles per thread, 0 means endless
+    pub(crate) loops: u64,
+    /// Stop the run after this time, None runs until loops are done
+    pub(crate) duration: Option<Duration>,
+    /// Real-time priority for SCHED_FIFO and SCHED_RR of the first thread
+    pub(crate) priority: i32,
+    /// Each further thread gets one priority lower, like cyclictest without
+    /// -h and --smp
+    pub(crate) descending_priority: bool,
+    pub(crate) policy: Policy,
+    /// Needed for Policy::Deadline
+    pub(crate) deadline: Option<DeadlineParam>,
+    /// Thread N runs on cpus[N % cpus.len()], None leaves the threads unpinned
+    pub(crate) cpus: Option<Vec<usize>>,
+    /// Stop all threads and the kernel tracer when a latency exceeds this in µs
+    pub(crate) breaktrace_us: Option<u64>,
+    /// Number of histogram buckets
+    pub(crate) hist_size: usize,
+    /// Width of a histogram bucket in ns
+    pub(crate) hist_bucket_ns: u64,
+    pub(crate) timer_mode: TimerMode,
+    pub(crate) clock: Clock,
+    /// Wakeup primitive of the clock_nanosleep measurements
+    pub(crate) sleep_backend: SleepBackend,
+    /// Spin margin of SleepBackend::Spin in µs
+    pub(crate) spin_margin_us: u32,
+    /// Print the setup steps and diagnostics
+    pub(crate) verbose: bool,
+    /// Run under SCHED_OTHER without mlockall instead of refusing when
+    /// privileges are missing
+    pub(crate) allow_degraded: bool,
+    /// Stack size of the measurement threads in bytes, None for the default
+    pub(crate) stack_size: Option<usize>,
+    /// Tune malloc, fault in the heap reserve and the thread stacks
+    pub(crate) prefault: bool,
+    /// Heap faulted in with prefault in bytes
+    pub(crate) heap_reserve: usize,
+    /// Sample getrusage every this many cycles, 0 for only the totals
+    pub(crate) rusage_interval: u64,
+    /// Count perf events around the measurement loops
+    pub(crate) perf: bool,
+    /// Raw samples kept per thread, 0 records none
+    pub(crate) record_samples: usize,
+}
+
+impl Default for MeasurementConfig {
+    fn default() -> MeasurementConfig {
+        //! The defaults of the command line
+        MeasurementConfig {
+            measurement: MeasurementType::ClockNanosleepGettime,
+            num_threads: 12,
+            interval_us: 1000,
+            distance_us: 500,
+            loops: 10_000,
+            duration: None,
+            priority: 99,
+            descending_priority: true,
+            policy: Policy::Fifo,
+            deadline: None,
+            cpus: None,
+            breaktrace_us: None,
+            hist_size: 15,
+            hist_bucket_ns: 1_000,
+            timer_mode: TimerMode::Absolute,
+            clock: Clock::Monotonic,
+            sleep_backend: SleepBackend::ClockNanosleep,
+            spin_margin_us: 20,
+            verbose: false,
+            allow_degraded: false,
+            stack_size: None,
+            prefault: false,
+            heap_reserve: 0,
+            rusage_interval: 0,
+            perf: false,
+            record_samples: 0,
+        }
+    }
+}
+
+impl MeasurementConfig {
+    pub fn new(measurement: MeasurementType) -> MeasurementConfig {
+        MeasurementConfig {
+            measurement,
+            ..Default::default()
+        }
+    }
+
+    pub fn measurement(self, measurement: MeasurementType) -> MeasurementConfig {
+        MeasurementConfig {
+            measurement,
+            ..self
+        }
+    }
+
+    pub fn threads(self, num_threads: usize) -> MeasurementConfig {
+        MeasurementConfig {
+            num_threads,
+            ..self
+        }
+    }
+
+    pub fn interval_us(self, interval_us: u32) -> MeasurementConfig {
+        MeasurementConfig {
+            interval_us,
+            ..self
+        }
+    }
+
+    pub fn distance_us(self, distance_us: u32) -> MeasurementConfig {
+        MeasurementConfig {
+            distance_us,
+            ..self
+        }
+    }
+
+    pub fn loops(self, loops: u64) -> MeasurementConfig {
+        //! Cycles per thread, 0 runs until the duration is over
+        MeasurementConfig { loops, ..self }
+    }
+
+    pub fn duration(self, duration: Duration) -> MeasurementConfig {
+        MeasurementConfig {
+            duration: Some(duration),
+            ..self
+        }
+    }
+
+    pub fn priority(self, priority: i32) -> MeasurementConfig {
+        MeasurementConfig { priority, ..self }
+    }
+
+    pub fn descending_priority(self, descending_priority: bool) -> MeasurementConfig {
+        //! Lower the priority by one per thread, not below 1, instead of
+        //! running all threads at the same priority
+        MeasurementConfig {
+            descending_priority,
+            ..self
+        }
+    }
+
+    pub fn policy(self, policy: Policy) -> MeasurementConfig {
+        MeasurementConfig { policy, ..self }
+    }
+
+    pub fn deadline(self, deadline: DeadlineParam) -> MeasurementConfig {
+        //! The parameters for Policy::Deadline
+        MeasurementConfig {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    pub fn cpus(self, cpus: Vec<usize>) -> MeasurementConfig {
+        //! Pin thread N to cpus[N % cpus.len()]
+        MeasurementConfig {
+            cpus: Some(cpus),
+            ..self
+        }
+    }
+
+    pub fn breaktrace_us(self, breaktrace_us: u64) -> MeasurementConfig {
+        MeasurementConfig {
+            breaktrace_us: Some(breaktrace_us),
+            ..self
+        }
+    }
+
+    pub fn histogram(self, hist_size: usize, hist_bucket_ns: u64) -> MeasurementConfig {
+        //! Number of buckets and their width in ns
+        MeasurementConfig {
+            hist_size,
+            hist_bucket_ns,
+            ..self
+        }
+    }
+
+    pub fn timer_mode(self, timer_mode: TimerMode) -> MeasurementConfig {
+        MeasurementConfig { timer_mode, ..self }
+    }
+
+    pub fn clock(self, clock: Clock) -> MeasurementConfig {
+        MeasurementConfig { clock, ..self }
+    }
+
+    pub fn sleep_backend(self, sleep_backend: SleepBackend) -> MeasurementConfig {
+        MeasurementConfig {
+            sleep_backend,
+            ..self
+        }
+    }
+
+    pub fn spin_margin_us(self, spin_margin_us: u32) -> MeasurementConfig {
+        MeasurementConfig {
+            spin_margin_us,
+            ..self
+        }
+    }
+
+    pub fn verbose(self, verbose: bool) -> MeasurementConfig {
+        //! Print the setup steps like the command line does, off by default
+        MeasurementConfig { verbose, ..self }
+    }
+
+    pub fn allow_degraded(self, allow_degraded: bool) -> MeasurementConfig {
+        //! Without real-time privileges fall back to SCHED_OTHER instead of
+        //! failing, see Report::degraded
+        MeasurementConfig {
+            allow_degraded,
+            ..self
+        }
+    }
+
+    pub fn stack_size(self, stack_size: usize) -> MeasurementConfig {
+        //! Stack size of the measurement threads in bytes, at least 128 KiB
+        MeasurementConfig {
+            stack_size: Some(stack_size),
+            ..self
+        }
+    }
+
+    pub fn prefault(self, prefault: bool) -> MeasurementConfig {
+        //! Before measuring, disable heap trimming and mmap in malloc, fault
+        //! in the heap reserve and let each thread touch its stack
+        MeasurementConfig { prefault, ..self }
+    }
+
+    pub fn heap_reserve(self, heap_reserve: usize) -> MeasurementConfig {
+        //! Bytes of heap to fault in with prefault
+        MeasurementConfig {
+            heap_reserve,
+            ..self
+        }
+    }
+
+    pub fn rusage_interval(self, rusage_interval: u64) -> MeasurementConfig {
+        //! Sample the page faults and context switches every this many
+        //! cycles and attribute them to the spikes, see
+        //! ThreadStats::outlier_rusage
+        MeasurementConfig {
+            rusage_interval,
+            ..self
+        }
+    }
+
+    pub fn perf(self, perf: bool) -> MeasurementConfig {
+        //! Count context switches, migrations, page faults, instructions and
+        //! cache misses of each thread, see ThreadStats::perf
+        MeasurementConfig { perf, ..self }
+    }
+
+    pub fn record_samples(self, record_samples: usize) -> MeasurementConfig {
+        //! Keep the last this many samples of each thread in a buffer that
+        //! is allocated before measuring, see Report::samples
+        MeasurementConfig {
+            record_samples,
+            ..self
+        }
+    }
+
+    pub(crate) fn thread_priority(&self, thread: usize) -> i32 {
+        //! The priority of a thread, 0 for the policies without one
+        match (self.policy.is_realtime(), self.descending_priority) {
+            (false, _) => 0,
+            (true, false) => self.priority,
+            (true, true) => (self.priority - thread as i32).max(1),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        //! Reject the values that the measurement threads can't work with
+        if self.interval_us == 0 {
+            return Err("The interval needs at least 1 µs");
+        }
+        if self.hist_bucket_ns == 0 {
+            return Err("The histogram buckets need a width of at least 1 ns");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn interval_ns(&self, thread: usize) -> u64 {
+        //! The interval of a thread in ns
+        (self.interval_us as u64 + thread as u64 * self.distance_us as u64) * 1_000
+    }
+
+    pub(crate) fn degraded(self) -> MeasurementConfig {
+        //! What is left of the config without real-time privileges
+        MeasurementConfig {
+            policy: Policy::Other,
+            priority: 0,
+            deadline: None,
+            ..self
+        }
+    }
+}
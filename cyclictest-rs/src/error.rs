@@ -0,0 +1,116 @@
+//! Errors of the system setup calls
+//!
+//! RtError is Copy and holds only static strings, so creating and returning
+//! it does not allocate. This matters in the measurement threads, where an
+//! error must not disturb the other threads.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use errno::{errno, Errno};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtError {
+    /// A system call failed
+    Syscall {
+        syscall: &'static str,
+        errno: i32,
+        /// What usually helps, empty if there is nothing to suggest
+        hint: &'static str,
+    },
+    /// The CPU does not exist or is not online
+    CpuNotOnline { cpu: usize },
+    /// Rejected without calling into the kernel
+    Invalid {
+        call: &'static str,
+        reason: &'static str,
+    },
+}
+
+fn hint(syscall: &str, errno: i32) -> &'static str {
+    match (syscall, errno) {
+        ("sched_setscheduler" | "sched_setattr", libc::EPERM) => {
+            "needs CAP_SYS_NICE or RLIMIT_RTPRIO"
+        }
+        ("sched_setscheduler", libc::EINVAL) => "priority is out of range for the policy",
+        ("sched_setattr", libc::EBUSY) => {
+            "SCHED_DEADLINE admission control failed, lower the runtime or the number of threads"
+        }
+        ("mlockall", libc::EPERM | libc::ENOMEM | libc::EAGAIN) => {
+            "needs CAP_IPC_LOCK or a larger RLIMIT_MEMLOCK"
+        }
+        ("open /dev/cpu_dma_latency", libc::EACCES | libc::EPERM) => {
+            "needs root to write /dev/cpu_dma_latency"
+        }
+        ("open /dev/cpu_dma_latency", libc::ENOENT) => "the kernel has no PM QoS support",
+        ("timer_create" | "timerfd_create", libc::EINVAL) => "the clock is not supported",
+        ("epoll_pwait2", libc::ENOSYS) => "needs Linux 5.11",
+        _ => "",
+    }
+}
+
+impl RtError {
+    pub fn syscall(syscall: &'static str, errno: i32) -> RtError {
+        RtError::Syscall {
+            syscall,
+            errno,
+            hint: hint(syscall, errno),
+        }
+    }
+
+    pub fn last(syscall: &'static str) -> RtError {
+        //! Error of a call that has just failed and set errno
+        RtError::syscall(syscall, errno().0)
+    }
+
+    pub fn from_io(syscall: &'static str, e: &io::Error) -> RtError {
+        RtError::syscall(syscall, e.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            RtError::Syscall { hint: "", .. } => None,
+            RtError::Syscall { hint, .. } => Some(hint),
+            RtError::CpuNotOnline { .. } => Some("see /sys/devices/system/cpu/online"),
+            RtError::Invalid { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for RtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RtError::Syscall { syscall, errno, .. } => {
+                write!(f, "{} fails: {} (errno {})", syscall, Errno(*errno), errno)?
+            }
+            RtError::CpuNotOnline { cpu } => write!(f, "CPU {} not online", cpu)?,
+            RtError::Invalid { call, reason } => write!(f, "{}: {}", call, reason)?,
+        }
+        match self.hint() {
+            Some(hint) => write!(f, ", {}", hint),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for RtError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rt_error_display() {
+        let e = RtError::syscall("sched_setscheduler", libc::EPERM);
+        assert_eq!(e.hint(), Some("needs CAP_SYS_NICE or RLIMIT_RTPRIO"));
+        assert_eq!(
+            e.to_string(),
+            "sched_setscheduler fails: Operation not permitted (errno 1), \
+             needs CAP_SYS_NICE or RLIMIT_RTPRIO"
+        );
+        let e = RtError::CpuNotOnline { cpu: 99 };
+        assert!(e.to_string().starts_with("CPU 99 not online"));
+        assert_eq!(RtError::syscall("sigaction", libc::EINVAL).hint(), None);
+    }
+}
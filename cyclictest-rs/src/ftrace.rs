@@ -0,0 +1,127 @@
+//! Breaktrace support, stop the run and the kernel tracer on a latency spike
+//!
+//! Like cyclictest -b: the first thread that sees a latency above the
+//! threshold writes a marker into the ftrace buffer and switches tracing off,
+//! so that the trace ends with the kernel path behind the spike.
+//!
+//! https://www.kernel.org/doc/html/latest/trace/ftrace.html
+
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const TRACEFS_PATHS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// Where and when the threshold was exceeded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BreakInfo {
+    pub thread_num: u32,
+    pub cycle: u64,
+    pub latency_ns: u64,
+}
+
+pub struct Breaktrace {
+    threshold_ns: u64,
+    trace_marker: Option<File>,
+    tracing_on: Option<File>,
+    hit: AtomicBool,
+    info: Mutex<Option<BreakInfo>>,
+}
+
+fn open_tracefs(name: &str) -> Option<File> {
+    TRACEFS_PATHS.iter().find_map(|dir| {
+        OpenOptions::new()
+            .write(true)
+            .open(Path::new(dir).join(name))
+            .ok()
+    })
+}
+
+impl Breaktrace {
+    pub fn new(threshold_us: u64) -> Breaktrace {
+        //! Open the tracefs files up front, the measurement threads must not
+        //! do this when they hit the threshold.
+        let trace_marker = open_tracefs("trace_marker");
+        let tracing_on = open_tracefs("tracing_on");
+        if trace_marker.is_none() || tracing_on.is_none() {
+            verbose!("Tracefs is not available, breaktrace will only stop the measurement");
+        }
+        Breaktrace {
+            threshold_ns: threshold_us * 1_000,
+            trace_marker,
+            tracing_on,
+            hit: AtomicBool::new(false),
+            info: Mutex::new(None),
+        }
+    }
+
+    pub fn check(&self, thread_num: u32, cycle: u64, latency_ns: u64) -> bool {
+        //! Returns true when the threshold is exceeded and the run should stop
+        //!
+        //! Only the first hit is recorded and marked in the trace. This is
+        //! called in the measurement loop, the hot path does no allocation.
+        if latency_ns <= self.threshold_ns {
+            return false;
+        }
+        if self.hit.swap(true, Ordering::SeqCst) {
+            return true;
+        }
+        if let Some(mut marker) = self.trace_marker.as_ref() {
+            // One write per marker, formatted on the stack
+            let mut buf = [0u8; 128];
+            let mut cursor = Cursor::new(&mut buf[..]);
+            let _ = writeln!(
+                cursor,
+                "hit latency threshold ({} > {}) thread {} cycle {}",
+                latency_ns / 1_000,
+                self.threshold_ns / 1_000,
+                thread_num,
+                cycle
+            );
+            let len = cursor.position() as usize;
+            let _ = marker.write_all(&buf[..len]);
+        }
+        if let Some(mut tracing_on) = self.tracing_on.as_ref() {
+            let _ = tracing_on.write_all(b"0");
+        }
+        *self.info.lock().unwrap() = Some(BreakInfo {
+            thread_num,
+            cycle,
+            latency_ns,
+        });
+        true
+    }
+
+    pub fn info(&self) -> Option<BreakInfo> {
+        //! The first hit, if there was any
+        *self.info.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_breaktrace_check() {
+        let bt = Breaktrace {
+            threshold_ns: 10_000,
+            trace_marker: None,
+            tracing_on: None,
+            hit: AtomicBool::new(false),
+            info: Mutex::new(None),
+        };
+        assert!(!bt.check(0, 1, 10_000));
+        assert_eq!(bt.info(), None);
+        assert!(bt.check(1, 2, 10_001));
+        assert!(bt.check(2, 3, 20_000));
+        let expected = BreakInfo {
+            thread_num: 1,
+            cycle: 2,
+            latency_ns: 10_001,
+        };
+        assert_eq!(bt.info(), Some(expected));
+    }
+}
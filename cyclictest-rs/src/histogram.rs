@@ -0,0 +1,141 @@
+//! Histogram output in the format of the original cyclictest -h
+//!
+//! The OSADL latency plot scripts parse this format, so keep it byte
+//! compatible with print_hist() of rt-tests.
+//!
+//! https://git.kernel.org/pub/scm/utils/rt-tests/rt-tests.git/tree/src/cyclictest/cyclictest.c
+
+use std::io::{self, Write};
+
+use crate::ThreadStats;
+
+pub(crate) fn write_histogram(
+    out: &mut dyn Write,
+    threads: &[ThreadStats],
+    unit_ns: u64,
+) -> io::Result<()> {
+    //! Write the histogram of all threads, latencies are given in unit_ns
+    //!
+    //! Each row is the lower bound of the bucket and a space, followed by
+    //! the counts of the threads separated by tabs.
+    let hist_size = threads.first().map_or(0, |t| t.hist.len());
+    let mut totals = vec![0u64; threads.len()];
+
+    writeln!(out, "# Histogram")?;
+    for bucket in 0..hist_size {
+        let bucket_ns = threads[0].bucket_ns;
+        write!(out, "{:06} ", bucket as u64 * bucket_ns / unit_ns)?;
+        for (i, (thread, total)) in threads.iter().zip(totals.iter_mut()).enumerate() {
+            let separator = if i > 0 { "\t" } else { "" };
+            write!(out, "{}{:06}", separator, thread.hist[bucket])?;
+            *total += thread.hist[bucket] as u64;
+        }
+        writeln!(out)?;
+    }
+
+    write!(out, "# Total:")?;
+    for total in &totals {
+        write!(out, " {:09}", total)?;
+    }
+    writeln!(out)?;
+
+    write!(out, "# Min Latencies:")?;
+    for thread in threads {
+        let min = match thread.cycles {
+            0 => 0,
+            _ => thread.min,
+        };
+        write!(out, " {:05}", min / unit_ns)?;
+    }
+    writeln!(out)?;
+
+    write!(out, "# Avg Latencies:")?;
+    for thread in threads {
+        write!(out, " {:05}", thread.average() / unit_ns)?;
+    }
+    writeln!(out)?;
+
+    write!(out, "# Max Latencies:")?;
+    for thread in threads {
+        write!(out, " {:05}", thread.max / unit_ns)?;
+    }
+    writeln!(out)?;
+
+    write!(out, "# Histogram Overflows:")?;
+    for thread in threads {
+        write!(out, " {:05}", thread.overflows)?;
+    }
+    writeln!(out)?;
+
+    writeln!(out, "# Histogram Overflow at cycle number:")?;
+    for (i, thread) in threads.iter().enumerate() {
+        write!(out, "# Thread {}:", i)?;
+        for cycle in &thread.outliers {
+            write!(out, " {:05}", cycle)?;
+        }
+        let others = thread.overflows as usize - thread.outliers.len();
+        if others > 0 {
+            write!(out, " # {:05} others", others)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::SharedThreadStats;
+
+    #[test]
+    fn test_write_histogram() {
+        let t0 = SharedThreadStats::new(3, 1_000);
+        for (latency, cycle) in [(1_500, 1), (1_200, 2), (2_100, 3), (5_000, 4)] {
+            t0.record(latency, cycle);
+        }
+        let t1 = SharedThreadStats::new(3, 1_000);
+        t1.record(500, 1);
+
+        let mut out = vec![];
+        write_histogram(&mut out, &[t0.snapshot(), t1.snapshot()], 1_000).unwrap();
+        let expected = "\
+# Histogram
+000000 000000\t000001
+000001 000002\t000000
+000002 000001\t000000
+# Total: 000000003 000000001
+# Min Latencies: 00001 00000
+# Avg Latencies: 00002 00000
+# Max Latencies: 00005 00000
+# Histogram Overflows: 00001 00000
+# Histogram Overflow at cycle number:
+# Thread 0: 00004
+# Thread 1:
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_write_histogram_ns_buckets() {
+        // 4 buckets of 250 ns, reported in ns
+        let t0 = SharedThreadStats::new(4, 250);
+        t0.record(300, 1);
+        let mut out = vec![];
+        write_histogram(&mut out, &[t0.snapshot()], 1).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("000250 000001\n000500 000000\n"));
+        assert!(out.contains("# Min Latencies: 00300\n"));
+    }
+
+    #[test]
+    fn test_write_histogram_others() {
+        let t0 = SharedThreadStats::new(1, 1_000);
+        for cycle in 1..=3 {
+            t0.record(10_000, cycle);
+        }
+        let mut out = vec![];
+        write_histogram(&mut out, &[t0.snapshot()], 1_000).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.ends_with("# Thread 0: 00001 # 00002 others\n"));
+    }
+}
@@ -0,0 +1,384 @@
+//! Result file in the JSON schema of rt-tests --json
+//!
+//! The common part is written like rt_write_json() in rt-utils.c and the
+//! thread part like write_stats() of cyclictest, so that tools that ingest
+//! the output of the C cyclictest can read ours. Additional keys are only
+//! added, never renamed.
+//!
+//! https://git.kernel.org/pub/scm/utils/rt-tests/rt-tests.git/tree/src/lib/rt-utils.c
+
+use std::error::Error;
+use std::ffi::CStr;
+use std::fs;
+use std::io::Write;
+use std::mem;
+
+use crate::{Check, MeasurementConfig, Rusage, SleepBackend, ThreadStats};
+
+const FILE_VERSION: u32 = 1;
+
+pub(crate) fn timestamp() -> String {
+    //! Local time like get_timestamp() of rt-utils, e.g. 2024-02-01T12:00:00
+    let mut buf = [0u8; 64];
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    let len = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+        libc::strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            c"%Y-%m-%dT%H:%M:%S".as_ptr(),
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn escape(s: &str) -> String {
+    //! Escape a string for a JSON string literal
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// System information like in the sysinfo object of rt-tests
+pub(crate) struct SysInfo {
+    pub sysname: String,
+    pub nodename: String,
+    pub release: String,
+    pub version: String,
+    pub machine: String,
+    pub realtime: bool,
+}
+
+impl SysInfo {
+    pub fn new() -> Result<SysInfo, Box<dyn Error>> {
+        let mut uts: libc::utsname = unsafe { mem::zeroed() };
+        if unsafe { libc::uname(&mut uts) } != 0 {
+            return Err("uname fails".into());
+        }
+        let field = |f: &[libc::c_char]| {
+            unsafe { CStr::from_ptr(f.as_ptr()) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        let realtime = fs::read_to_string("/sys/kernel/realtime").is_ok_and(|rt| rt.trim() == "1");
+        Ok(SysInfo {
+            sysname: field(&uts.sysname),
+            nodename: field(&uts.nodename),
+            release: field(&uts.release),
+            version: field(&uts.version),
+            machine: field(&uts.machine),
+            realtime,
+        })
+    }
+}
+
+/// Everything that goes into the result file besides the thread statistics
+pub(crate) struct JsonInfo<'a> {
+    pub cmdline: &'a str,
+    pub start_time: &'a str,
+    pub end_time: &'a str,
+    pub return_code: i32,
+    /// Latencies in ns instead of µs
+    pub nsecs: bool,
+    pub sysinfo: &'a SysInfo,
+    /// Results of the preflight checks, see check.rs
+    pub checks: &'a [Check],
+    /// Missing privileges of a degraded run, empty for a real-time run
+    pub degraded: &'a [&'static str],
+    /// /dev/cpu_dma_latency was held at 0 during the run
+    pub latency_target: bool,
+}
+
+fn write_rusage(out: &mut dyn Write, indent: &str, rusage: &Rusage) -> Result<(), Box<dyn Error>> {
+    //! The counters as keys, without a newline after the last one
+    writeln!(out, "{}\"minor_faults\": {},", indent, rusage.minor_faults)?;
+    writeln!(out, "{}\"major_faults\": {},", indent, rusage.major_faults)?;
+    writeln!(
+        out,
+        "{}\"voluntary_switches\": {},",
+        indent, rusage.voluntary_switches
+    )?;
+    write!(
+        out,
+        "{}\"involuntary_switches\": {}",
+        indent, rusage.involuntary_switches
+    )?;
+    Ok(())
+}
+
+pub(crate) fn write_json(
+    out: &mut dyn Write,
+    info: &JsonInfo,
+    run: &MeasurementConfig,
+    threads: &[ThreadStats],
+) -> Result<(), Box<dyn Error>> {
+    //! Write the results, latencies are in µs or in ns with info.nsecs
+    let unit_ns: u64 = match info.nsecs {
+        true => 1,
+        false => 1_000,
+    };
+    let sys = info.sysinfo;
+
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"file_version\": {},", FILE_VERSION)?;
+    // The colons in the keys are there in rt-tests as well
+    writeln!(out, "  \"cmdline:\": \"{}\",", escape(info.cmdline))?;
+    writeln!(
+        out,
+        "  \"rt_test_version:\": \"{}\",",
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(out, "  \"start_time\": \"{}\",", escape(info.start_time))?;
+    writeln!(out, "  \"end_time\": \"{}\",", escape(info.end_time))?;
+    writeln!(out, "  \"return_code\": {},", info.return_code)?;
+    let degraded: Vec<String> = info
+        .degraded
+        .iter()
+        .map(|missing| format!("\"{}\"", escape(missing)))
+        .collect();
+    writeln!(out, "  \"degraded\": [{}],", degraded.join(", "))?;
+    writeln!(out, "  \"latency_target\": {},", info.latency_target as u32)?;
+    writeln!(out, "  \"sysinfo\": {{")?;
+    writeln!(out, "    \"sysname\": \"{}\",", escape(&sys.sysname))?;
+    writeln!(out, "    \"nodename\": \"{}\",", escape(&sys.nodename))?;
+    writeln!(out, "    \"release\": \"{}\",", escape(&sys.release))?;
+    writeln!(out, "    \"version\": \"{}\",", escape(&sys.version))?;
+    writeln!(out, "    \"machine\": \"{}\",", escape(&sys.machine))?;
+    writeln!(out, "    \"realtime\": {}", sys.realtime as u32)?;
+    writeln!(out, "  }},")?;
+    writeln!(out, "  \"checks\": {{")?;
+    for (i, check) in info.checks.iter().enumerate() {
+        writeln!(out, "    \"{}\": {{", check.name)?;
+        writeln!(out, "      \"status\": \"{}\",", check.status.name())?;
+        writeln!(out, "      \"value\": \"{}\",", escape(&check.value))?;
+        writeln!(
+            out,
+            "      \"explanation\": \"{}\"",
+            escape(&check.explanation)
+        )?;
+        let comma = if i == info.checks.len() - 1 { "" } else { "," };
+        writeln!(out, "    }}{}", comma)?;
+    }
+    writeln!(out, "  }},")?;
+
+    writeln!(out, "  \"num_threads\": {},", threads.len())?;
+    writeln!(out, "  \"resolution_in_ns\": {},", info.nsecs as u32)?;
+    writeln!(out, "  \"parameters\": {{")?;
+    writeln!(out, "    \"interval\": {},", run.interval_us)?;
+    writeln!(out, "    \"distance\": {},", run.distance_us)?;
+    writeln!(out, "    \"loops\": {},", run.loops)?;
+    let duration = run.duration.map_or(0, |d| d.as_secs());
+    writeln!(out, "    \"duration\": {},", duration)?;
+    writeln!(out, "    \"priority\": {},", run.priority)?;
+    writeln!(out, "    \"policy\": \"{:?}\",", run.policy)?;
+    writeln!(out, "    \"timer_mode\": \"{:?}\",", run.timer_mode)?;
+    writeln!(out, "    \"clock\": \"{}\",", run.clock.name())?;
+    writeln!(
+        out,
+        "    \"sleep_backend\": \"{}\",",
+        run.sleep_backend.name()
+    )?;
+    if run.sleep_backend == SleepBackend::Spin {
+        writeln!(out, "    \"spin_margin\": {},", run.spin_margin_us)?;
+    }
+    writeln!(out, "    \"histogram\": {},", run.hist_size)?;
+    writeln!(out, "    \"histogram_bucket_ns\": {}", run.hist_bucket_ns)?;
+    writeln!(out, "  }},")?;
+
+    writeln!(out, "  \"thread\": {{")?;
+    for (i, thread) in threads.iter().enumerate() {
+        writeln!(out, "    \"{}\": {{", i)?;
+        // Only the buckets with entries, keyed by their lower bound
+        write!(out, "      \"histogram\": {{")?;
+        let mut comma = false;
+        for (bucket, count) in thread.hist.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            write!(out, "{}", if comma { ",\n" } else { "\n" })?;
+            let latency = bucket as u64 * thread.bucket_ns / unit_ns;
+            write!(out, "        \"{}\": {}", latency, count)?;
+            comma = true;
+        }
+        if comma {
+            writeln!(out)?;
+        }
+        writeln!(out, "      }},")?;
+        let min = match thread.cycles {
+            0 => 0,
+            _ => thread.min,
+        };
+        writeln!(out, "      \"cycles\": {},", thread.cycles)?;
+        writeln!(out, "      \"min\": {},", min / unit_ns)?;
+        writeln!(out, "      \"max\": {},", thread.max / unit_ns)?;
+        let avg = match thread.cycles {
+            0 => 0f64,
+            cycles => thread.sum as f64 / cycles as f64 / unit_ns as f64,
+        };
+        writeln!(out, "      \"avg\": {:.2},", avg)?;
+        writeln!(out, "      \"overflows\": {},", thread.overflows)?;
+        writeln!(out, "      \"overruns\": {},", thread.overruns)?;
+        write_rusage(out, "      ", &thread.rusage)?;
+        writeln!(out, ",")?;
+        if thread
+            .perf
+            .events()
+            .iter()
+            .any(|(_, count)| count.is_some())
+        {
+            writeln!(out, "      \"perf\": {{")?;
+            let events = thread.perf.events();
+            for (i, (name, count)) in events.iter().enumerate() {
+                let count = count.map_or("null".to_string(), |c| c.to_string());
+                let comma = if i == events.len() - 1 { "" } else { "," };
+                writeln!(out, "        \"{}\": {}{}", name, count, comma)?;
+            }
+            writeln!(out, "      }},")?;
+        }
+        let spikes: Vec<_> = thread
+            .outliers
+            .iter()
+            .zip(&thread.outlier_rusage)
+            .filter_map(|(cycle, rusage)| rusage.map(|rusage| (cycle, rusage)))
+            .collect();
+        if run.rusage_interval > 0 && thread.overflows > 0 {
+            // All overflows, the list below has the first outliers only
+            writeln!(
+                out,
+                "      \"disturbed_spikes\": {},",
+                thread.disturbed_overflows
+            )?;
+        }
+        if !spikes.is_empty() {
+            // The rusage of the sample window of each overflow
+            writeln!(out, "      \"spikes\": [")?;
+            for (i, (cycle, rusage)) in spikes.iter().enumerate() {
+                writeln!(out, "        {{")?;
+                writeln!(out, "          \"cycle\": {},", cycle)?;
+                write_rusage(out, "          ", rusage)?;
+                writeln!(out)?;
+                let comma = if i == spikes.len() - 1 { "" } else { "," };
+                writeln!(out, "        }}{}", comma)?;
+            }
+            writeln!(out, "      ],")?;
+        }
+        if thread.wakeups > 0 {
+            // The latencies above are the deadline accuracy after spinning
+            writeln!(out, "      \"wakeups\": {},", thread.wakeups)?;
+            writeln!(
+                out,
+                "      \"wakeup_min\": {},",
+                thread.wakeup_min / unit_ns
+            )?;
+            writeln!(
+                out,
+                "      \"wakeup_max\": {},",
+                thread.wakeup_max / unit_ns
+            )?;
+            let wakeup_avg = thread.wakeup_sum as f64 / thread.wakeups as f64 / unit_ns as f64;
+            writeln!(out, "      \"wakeup_avg\": {:.2},", wakeup_avg)?;
+            writeln!(out, "      \"late_wakeups\": {},", thread.late_wakeups)?;
+        }
+        let cpu = run
+            .cpus
+            .as_ref()
+            .map_or(-1, |cpus| cpus[i % cpus.len()] as i64);
+        writeln!(out, "      \"cpu\": {},", cpu)?;
+        writeln!(out, "      \"node\": -1")?;
+        writeln!(
+            out,
+            "    }}{}",
+            if i == threads.len() - 1 { "" } else { "," }
+        )?;
+    }
+    writeln!(out, "  }}")?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CheckStatus, SharedThreadStats};
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a \"b\" c\\d\n"), "a \\\"b\\\" c\\\\d\\n");
+        assert_eq!(escape("\u{1}"), "\\u0001");
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let ts = timestamp();
+        assert_eq!(ts.len(), 19);
+        assert_eq!(&ts[10..11], "T");
+    }
+
+    #[test]
+    fn test_write_json() -> Result<(), Box<dyn Error>> {
+        let sysinfo = SysInfo::new()?;
+        let info = JsonInfo {
+            cmdline: "cyclictest-rs --json \"x\"",
+            start_time: "2024-02-01T12:00:00",
+            end_time: "2024-02-01T12:00:10",
+            return_code: 0,
+            nsecs: false,
+            sysinfo: &sysinfo,
+            checks: &[Check {
+                name: "smt".to_string(),
+                status: CheckStatus::Pass,
+                value: "off".to_string(),
+                explanation: "one thread per core".to_string(),
+            }],
+            degraded: &["CAP_IPC_LOCK or unlimited RLIMIT_MEMLOCK"],
+            latency_target: false,
+        };
+        let run = MeasurementConfig {
+            cpus: Some(vec![2, 3]),
+            ..Default::default()
+        };
+        let t0 = SharedThreadStats::new(4, 1_000);
+        t0.record(1_500, 1);
+        t0.record(3_000, 2);
+        let t1 = SharedThreadStats::new(4, 1_000);
+
+        let mut out = vec![];
+        write_json(&mut out, &info, &run, &[t0.snapshot(), t1.snapshot()])?;
+        let out = String::from_utf8(out)?;
+        assert!(out.starts_with("{\n  \"file_version\": 1,\n"));
+        assert!(out.contains("\"cmdline:\": \"cyclictest-rs --json \\\"x\\\"\",\n"));
+        assert!(out.contains("  \"degraded\": [\"CAP_IPC_LOCK or unlimited RLIMIT_MEMLOCK\"],\n"));
+        assert!(out.contains("  \"latency_target\": 0,\n"));
+        assert!(out.contains(
+            "  \"checks\": {\n    \"smt\": {\n      \"status\": \"pass\",\n      \"value\": \"off\",\n"
+        ));
+        assert!(out.contains("\"num_threads\": 2,\n  \"resolution_in_ns\": 0,\n"));
+        assert!(out.contains(
+            "    \"0\": {\n      \"histogram\": {\n        \"1\": 1,\n        \"3\": 1\n      },\n"
+        ));
+        assert!(out.contains(
+            "\"cycles\": 2,\n      \"min\": 1,\n      \"max\": 3,\n      \"avg\": 2.25,\n"
+        ));
+        assert!(out.contains("    \"1\": {\n      \"histogram\": {      },\n"));
+        assert!(out.contains("\"cpu\": 3,\n      \"node\": -1\n    }\n  }\n}\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sysinfo() -> Result<(), Box<dyn Error>> {
+        let sys = SysInfo::new()?;
+        assert_eq!(sys.sysname, "Linux");
+        Ok(())
+    }
+}
@@ -1,16 +1,63 @@
 use std::error::Error;
+use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::mem;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use errno::errno;
 
+/// Print the setup steps and diagnostics, see MeasurementConfig::verbose
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::VERBOSE.load(std::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod analyze;
 mod benchmarks;
+mod check;
+mod config;
+mod error;
+mod ftrace;
+mod histogram;
+mod json;
+mod memory;
+mod monitor;
+mod perf;
+mod privileges;
+mod record;
+mod rusage;
+mod sleepers;
+
+pub use analyze::{analyze, AnalyzeOptions};
+pub use check::{run_checks, Check, CheckStatus};
+pub use config::MeasurementConfig;
+pub use error::RtError;
+pub use ftrace::BreakInfo;
+use ftrace::Breaktrace;
+pub use monitor::Monitor;
+use monitor::Threshold;
+use perf::AtomicPerfCounters;
+pub use perf::PerfCounters;
+pub use privileges::Privileges;
+use record::SampleRing;
+pub use record::{read_recording, write_recording, Recording, Sample, ThreadRecord};
+use rusage::AtomicRusage;
+pub use rusage::Rusage;
+pub use sleepers::SleepBackend;
+use sleepers::{to_timespec, Sleeper, Wakeup};
 
 /*
 
@@ -42,8 +89,13 @@ To-Do
 */
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, disable_help_flag = true,
+          args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Measure the wakeup of std::thread::sleep
     #[arg(long, default_value_t = false)]
     sleep: bool,
 
@@ -53,37 +105,326 @@ struct Args {
     #[arg(long, default_value_t = false)]
     nanosleepgettime: bool,
 
+    /// Measure the wakeup of a periodic POSIX timer with a thread directed signal
+    #[arg(long, default_value_t = false)]
+    posixtimer: bool,
+
+    /// Measure the wakeup of a periodic timerfd with a blocking read
+    #[arg(long, default_value_t = false)]
+    timerfd: bool,
+
     #[arg(long, default_value_t = false)]
     benchmarks: bool,
+
+    /// Spread the threads round-robin over the online CPUs, or with LIST over these CPUs, e.g. 0-3,8
+    #[arg(short, long, value_name = "LIST", num_args = 0..=1, default_missing_value = "",
+          value_parser = parse_affinity)]
+    affinity: Option<Affinity>,
+
+    /// One thread per online CPU, each pinned to its CPU
+    #[arg(long, default_value_t = false, conflicts_with_all = ["threads", "affinity"])]
+    smp: bool,
+
+    /// Stop the run and the kernel tracer when a latency exceeds this in µs
+    #[arg(short, long, value_name = "US")]
+    breaktrace: Option<u64>,
+
+    /// Write the results in the JSON format of rt-tests --json to FILE
+    #[arg(long, value_name = "FILE")]
+    json: Option<PathBuf>,
+
+    /// Wakeup primitives for --nanosleep and --nanosleepgettime, a list runs one measurement each
+    #[arg(
+        long,
+        value_enum,
+        value_name = "BACKEND",
+        value_delimiter = ',',
+        default_value = "clock-nanosleep"
+    )]
+    sleep_backend: Vec<SleepBackend>,
+
+    /// Time before the deadline in µs at which the spin sleep backend starts to spin
+    #[arg(long, value_name = "US", default_value_t = 20)]
+    spin_margin: u32,
+
+    /// Clock for sleeping and for measuring the latency
+    #[arg(short, long, value_enum, default_value_t = Clock::Monotonic)]
+    clock: Clock,
+
+    /// Use a relative timer instead of an absolute one (like cyclictest -r)
+    #[arg(short, long, default_value_t = false)]
+    relative: bool,
+
+    /// Number of measurement threads, without NUM one thread per available CPU
+    #[arg(short, long, value_name = "NUM", num_args = 0..=1,
+          default_value_t = 12, default_missing_value = "0")]
+    threads: usize,
+
+    /// Base interval of the first thread in µs
+    #[arg(short, long, value_name = "INTV", default_value_t = 1000,
+          value_parser = clap::value_parser!(u32).range(1..))]
+    interval: u32,
+
+    /// Distance of the thread intervals in µs
+    #[arg(short, long, value_name = "DIST", default_value_t = 500)]
+    distance: u32,
+
+    /// Number of cycles per thread, 0 runs endlessly [default: 10000, endless with --duration]
+    #[arg(short, long, value_name = "LOOPS")]
+    loops: Option<u64>,
+
+    /// Stop the run after the given time, e.g. 90, 10m, 24h (like cyclictest -D)
+    #[arg(short = 'D', long, value_name = "TIME", value_parser = parse_duration)]
+    duration: Option<Duration>,
+
+    /// Priority of the first thread, each further one gets one lower unless -h or --smp is given; 0 runs them with SCHED_OTHER
+    #[arg(short, long, value_name = "PRIO", default_value_t = 99,
+          value_parser = clap::value_parser!(i32).range(0..=99))]
+    priority: i32,
+
+    /// Scheduling policy [default: fifo, other with priority 0]
+    #[arg(long, value_enum)]
+    policy: Option<Policy>,
+
+    /// SCHED_DEADLINE runtime in µs
+    #[arg(long, value_name = "US", required_if_eq("policy", "deadline"))]
+    dl_runtime: Option<u32>,
+
+    /// SCHED_DEADLINE deadline in µs [default: period]
+    #[arg(long, value_name = "US", requires = "dl_runtime")]
+    dl_deadline: Option<u32>,
+
+    /// SCHED_DEADLINE period in µs [default: interval of the thread]
+    #[arg(long, value_name = "US", requires = "dl_runtime")]
+    dl_period: Option<u32>,
+
+    /// Print the histogram like cyclictest -h with this number of buckets [default table: 15]
+    #[arg(short = 'h', long, value_name = "US")]
+    histogram: Option<usize>,
+
+    /// Width of a histogram bucket in µs, or in ns with --nsecs
+    #[arg(long, value_name = "WIDTH", default_value_t = 1,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    hist_width: u64,
+
+    /// Report latencies and bucket widths in ns instead of µs (like cyclictest -N)
+    #[arg(short = 'N', long, default_value_t = false)]
+    nsecs: bool,
+
+    /// Run under SCHED_OTHER without mlockall when real-time privileges are missing
+    #[arg(long, default_value_t = false)]
+    allow_degraded: bool,
+
+    /// Stack size of the measurement threads in KiB [default: 2048]
+    #[arg(long, value_name = "KIB")]
+    stack_size: Option<usize>,
+
+    /// Tune malloc and fault in the heap reserve and thread stacks before measuring
+    #[arg(long, default_value_t = false)]
+    prefault: bool,
+
+    /// Heap to fault in with --prefault in MiB
+    #[arg(long, value_name = "MIB", default_value_t = 0, requires = "prefault")]
+    heap_reserve: usize,
+
+    /// Sample page faults and context switches every N cycles and attribute them to the spikes
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    rusage: u64,
+
+    /// Count context switches, migrations, page faults, instructions and cache misses with perf
+    #[arg(long, default_value_t = false)]
+    perf: bool,
+
+    /// Write the raw samples of every cycle to this binary file
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Samples per thread kept for --record, the oldest are overwritten [default: loops or 1000000]
+    #[arg(long, value_name = "N", requires = "record")]
+    record_size: Option<usize>,
+
+    /// Print help
+    #[arg(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the kernel and system settings for real-time measurements
+    Check(CheckArgs),
+    /// Analyze a file of --record without running the measurement again
+    Analyze(AnalyzeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct CheckArgs {
+    /// Print help
+    #[arg(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
+}
+
+#[derive(clap::Args, Debug)]
+struct AnalyzeArgs {
+    /// File written with --record
+    file: PathBuf,
+
+    /// Number of histogram buckets
+    #[arg(long, value_name = "N", default_value_t = 15)]
+    buckets: usize,
+
+    /// Width of a histogram bucket in µs, or in ns with --nsecs
+    #[arg(long, value_name = "WIDTH", default_value_t = 1,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    hist_width: u64,
+
+    /// Histogram bucket width and rows in ns instead of µs
+    #[arg(short = 'N', long, default_value_t = false)]
+    nsecs: bool,
+
+    /// Samples above this latency in µs are spikes [default: 99.9th percentile of all samples]
+    #[arg(long, value_name = "US")]
+    threshold: Option<u64>,
+
+    /// Number of the largest spikes in the timeline
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    spikes: usize,
+
+    /// Spikes of different threads within this many µs are correlated
+    #[arg(long, value_name = "US", default_value_t = 100)]
+    window: u64,
+
+    /// Print help
+    #[arg(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
+}
+
+#[derive(Clone, Debug)]
+enum Affinity {
+    /// Round-robin over the online CPUs
+    Online,
+    List(Vec<usize>),
+}
+
+fn parse_affinity(arg: &str) -> Result<Affinity, String> {
+    match arg {
+        "" => Ok(Affinity::Online),
+        list => Ok(Affinity::List(parse_cpu_list(list)?)),
+    }
 }
 
-pub fn setaffinity(cpu: u64) -> Result<(), Box<dyn Error>> {
+fn parse_duration(arg: &str) -> Result<Duration, String> {
+    //! Parse a duration with an optional suffix s, m, h or d, seconds without
+    let (value, factor) = match arg.char_indices().last() {
+        Some((i, 's')) => (&arg[..i], 1),
+        Some((i, 'm')) => (&arg[..i], 60),
+        Some((i, 'h')) => (&arg[..i], 60 * 60),
+        Some((i, 'd')) => (&arg[..i], 24 * 60 * 60),
+        _ => (arg, 1),
+    };
+    match value.parse::<u64>() {
+        Ok(v) if v > 0 => Ok(Duration::from_secs(v * factor)),
+        _ => Err(format!(
+            "invalid duration '{}', expected e.g. 90, 10m or 24h",
+            arg
+        )),
+    }
+}
+
+/// Set by the signal handler when SIGINT or SIGTERM arrives
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_stop_signal(_signum: libc::c_int) {
+    // Only async-signal-safe operations are allowed here
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn install_stop_handler() -> Result<(), RtError> {
+    //! Install handle_stop_signal for SIGINT and SIGTERM
+    // https://manpages.debian.org/bookworm/manpages-dev/sigaction.2.en.html
+    let mut action: libc::sigaction = unsafe { mem::zeroed() };
+    action.sa_sigaction = handle_stop_signal as *const () as libc::sighandler_t;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+    for signum in [libc::SIGINT, libc::SIGTERM] {
+        if unsafe { libc::sigaction(signum, &action, std::ptr::null_mut()) } != 0 {
+            return Err(RtError::last("sigaction"));
+        }
+    }
+    Ok(())
+}
+
+fn block_stop_signals() -> Result<(), RtError> {
+    //! Block SIGINT and SIGTERM for the calling thread
+    //!
+    //! Measurement threads call this, so that the signals are delivered to
+    //! the main thread and don't interrupt a running clock_nanosleep.
+    let mut sigset: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut sigset);
+        libc::sigaddset(&mut sigset, libc::SIGINT);
+        libc::sigaddset(&mut sigset, libc::SIGTERM);
+    }
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &sigset, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        code => Err(RtError::syscall("pthread_sigmask", code)),
+    }
+}
+
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>, String> {
+    //! Parse a Linux cpulist like "0-3,8,10-11" or "0-7:2" into sorted CPUs
+    // https://docs.kernel.org/admin-guide/kernel-parameters.html#cpu-lists
+    let invalid = || format!("invalid CPU list '{}'", list);
+    let mut cpus = vec![];
+    for group in list.trim().split(',') {
+        let (range, stride) = match group.split_once(':') {
+            Some((range, stride)) => (range, stride.parse::<usize>().map_err(|_| invalid())?),
+            None => (group, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (range, range),
+        };
+        let first = first.parse::<usize>().map_err(|_| invalid())?;
+        let last = last.parse::<usize>().map_err(|_| invalid())?;
+        if first > last || stride == 0 {
+            return Err(invalid());
+        }
+        cpus.extend((first..=last).step_by(stride));
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+pub fn online_cpus() -> Result<Vec<usize>, Box<dyn Error>> {
+    //! Read the online CPUs from sysfs
+    let list = fs::read_to_string("/sys/devices/system/cpu/online")?;
+    Ok(parse_cpu_list(&list)?)
+}
+
+pub fn setaffinity(cpu: u64) -> Result<(), RtError> {
     //! Set process affinity to given cpu
     // https://linux.die.net/man/2/sched_setaffinity
     // https://docs.rs/libc/0.2.153/libc/fn.sched_setaffinity.html
-    println!("Setting CPU affinity to {}", cpu);
+    verbose!("Setting CPU affinity to {}", cpu);
     let pid = 0;
     let cpusetsize: libc::size_t = libc::CPU_SETSIZE as libc::size_t;
     let mut cpuset: libc::cpu_set_t = unsafe { mem::zeroed() };
     unsafe { libc::CPU_ZERO(&mut cpuset) };
     let pmask: *mut libc::cpu_set_t = &mut cpuset;
-    unsafe { libc::CPU_SET(usize::try_from(cpu).unwrap(), &mut cpuset) };
-    match unsafe { libc::CPU_ISSET(usize::try_from(cpu).unwrap(), &cpuset) } {
-        true => (),
-        false => return Err("CPU_ISSET fails".into()),
+    let cpu = cpu as usize;
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(RtError::CpuNotOnline { cpu });
     }
+    unsafe { libc::CPU_SET(cpu, &mut cpuset) };
     match unsafe { libc::sched_setaffinity(pid, cpusetsize, pmask) } {
-        0 => (),
-        _ => {
-            let code = errno();
-            println!("setaffinity fails: {}, {}", code, code.0);
-            return Err(format!("setaffinity fails: {}, {}", code, code.0).into());
-        }
+        0 => Ok(()),
+        // The mask contains no online CPU
+        _ if errno().0 == libc::EINVAL => Err(RtError::CpuNotOnline { cpu }),
+        _ => Err(RtError::last("sched_setaffinity")),
     }
-    Ok(())
 }
 
-pub fn getscheduler() -> Result<&'static str, Box<dyn Error>> {
+pub fn getscheduler() -> Result<&'static str, RtError> {
     //! Get current scheduling policy
     // https://linux.die.net/man/2/sched_getscheduler
     let policy = match unsafe { libc::sched_getscheduler(0) } {
@@ -91,20 +432,28 @@ pub fn getscheduler() -> Result<&'static str, Box<dyn Error>> {
         libc::SCHED_IDLE => "SCHED_IDLE",
         libc::SCHED_FIFO => "SCHED_FIFO",
         libc::SCHED_RR => "SCHED_RR",
-        _ => return Err("Unexpected policy".into()),
+        libc::SCHED_BATCH => "SCHED_BATCH",
+        libc::SCHED_DEADLINE => "SCHED_DEADLINE",
+        -1 => return Err(RtError::last("sched_getscheduler")),
+        _ => {
+            return Err(RtError::Invalid {
+                call: "sched_getscheduler",
+                reason: "unexpected policy",
+            })
+        }
     };
-    println!("Getscheduler reports: {}", policy);
+    verbose!("Getscheduler reports: {}", policy);
     Ok(policy)
 }
 
-pub fn get_sched_get_priority_max() -> Result<(), Box<dyn Error>> {
+pub fn get_sched_get_priority_max() -> Result<(), RtError> {
     let policy = libc::SCHED_FIFO;
     let prio = unsafe { libc::sched_get_priority_max(policy) };
-    println!("Maximum prio is {}", prio);
+    verbose!("Maximum prio is {}", prio);
     Ok(())
 }
 
-pub fn block_alarm() -> Result<(), &'static str> {
+pub fn block_alarm() -> Result<(), RtError> {
     //! Block SIGALRM signal
 
     //sigemptyset(&sigset);
@@ -117,7 +466,7 @@ pub fn block_alarm() -> Result<(), &'static str> {
 
     //https://docs.rs/libc/0.2.153/libc/fn.sigemptyset.html
 
-    println!("Blocking Unix signals");
+    verbose!("Blocking Unix signals");
     let mut ret;
     let mut sigset: libc::sigset_t = unsafe { mem::zeroed() };
 
@@ -128,109 +477,120 @@ pub fn block_alarm() -> Result<(), &'static str> {
         ret = libc::sigemptyset(&mut sigset);
     }
     if ret != 0 {
-        return Err("sigemptyset fails");
+        return Err(RtError::last("sigemptyset"));
     }
 
     unsafe {
         ret = libc::sigaddset(&mut sigset, libc::SIGALRM);
     }
     if ret != 0 {
-        return Err("sigaddset fails");
+        return Err(RtError::last("sigaddset"));
     }
 
     unsafe {
         ret = libc::sigprocmask(libc::SIG_BLOCK, &sigset, &mut oldsigset);
     }
     if ret != 0 {
-        return Err("sigaddset fails");
+        return Err(RtError::last("sigprocmask"));
     };
     Ok(())
 }
 
-fn mlockall() -> Result<(), Box<dyn Error>> {
+fn mlockall() -> Result<(), RtError> {
     //! Lock all current and future memory pages
     // https://linux.die.net/man/3/mlockall
     // https://docs.rs/libc/latest/libc/fn.mlockall.html
     // TODO Maybe replace with nix version https://docs.rs/nix/0.27.1/nix/sys/mman/fn.mlockall.html
 
-    println!("Locking memory");
+    verbose!("Locking memory");
 
     let flags: libc::c_int = libc::MCL_CURRENT | libc::MCL_FUTURE;
     match unsafe { libc::mlockall(flags) } {
         0 => Ok(()),
-        -1 => {
-            let e = errno();
-            let code = e.0;
-            println!("Error {}: {}", code, e);
-            Err("Mlocall fails".into())
-        }
-        _ => Err("Mlocall fails strangely".into()),
+        _ => Err(RtError::last("mlockall")),
     }
 }
 
 /* Latency trick, see cyclictest*/
-fn set_latency_target() -> Result<File, Box<dyn Error>> {
-    println!("Disabling power management");
+fn set_latency_target() -> Result<File, RtError> {
+    verbose!("Disabling power management");
     let filename = String::from("/dev/cpu_dma_latency");
 
     // plain open did not work out
     //let mut f = File::open(filename)?;
-    let mut f = OpenOptions::new().write(true).open(filename)?;
+    let mut f = OpenOptions::new()
+        .write(true)
+        .open(filename)
+        .map_err(|e| RtError::from_io("open /dev/cpu_dma_latency", &e))?;
 
-    f.write_all(&[0, 0, 0, 0])?;
+    f.write_all(&[0, 0, 0, 0])
+        .map_err(|e| RtError::from_io("write /dev/cpu_dma_latency", &e))?;
     //f.set_len(4)?; // did not work out on the 6.10 Kernel
 
     Ok(f)
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-enum Policy {
+/// Scheduling policy of the measurement threads
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Policy {
     Other = libc::SCHED_OTHER as isize,
     Fifo = libc::SCHED_FIFO as isize,
     Rr = libc::SCHED_RR as isize,
+    Batch = libc::SCHED_BATCH as isize,
     Idle = libc::SCHED_IDLE as isize,
+    /// Needs runtime, deadline and period, see setscheduler_deadline
+    Deadline = libc::SCHED_DEADLINE as isize,
+}
+
+impl Policy {
+    pub fn is_realtime(self) -> bool {
+        //! True for the policies that take a real-time priority
+        matches!(self, Policy::Fifo | Policy::Rr)
+    }
+}
+
+/// Reservation of a SCHED_DEADLINE thread in µs
+///
+/// The period defaults to the interval of the thread and the deadline to
+/// the period. The kernel requires runtime <= deadline <= period.
+#[derive(Clone, Copy, Debug)]
+pub struct DeadlineParam {
+    pub runtime_us: u32,
+    pub deadline_us: Option<u32>,
+    pub period_us: Option<u32>,
 }
 
 #[allow(dead_code)]
-fn getpriority() -> Result<(), Box<dyn Error>> {
+fn getpriority() -> Result<(), RtError> {
     // Probably useless, only reports the nice default_value
     errno::set_errno(errno::Errno(0));
     match unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) } {
         -1 => {
-            let e = errno();
-            let code = e.0;
-            if code == 0 {
-                println!("getpriority reports {}", -1); // this can happen
+            if errno().0 == 0 {
+                verbose!("getpriority reports {}", -1); // this can happen
                 Ok(())
             } else {
-                println!("Error {}: {}", code, e);
-                Err("getpriority fails".into())
+                Err(RtError::last("getpriority"))
             }
         }
         p => {
-            println!("getpriority reports {}", p);
+            verbose!("getpriority reports {}", p);
             Ok(())
         }
     }
 }
 
-fn getschedparam() -> Result<(), Box<dyn Error>> {
+fn getschedparam() -> Result<(), RtError> {
     let pid: libc::c_int = 0;
     let mut params = libc::sched_param { sched_priority: 0 };
 
     match unsafe { libc::sched_getparam(pid, &mut params) } {
-        0 => println!("getparam reports prio {}", params.sched_priority),
-        _ => {
-            let e = errno();
-            let code = e.0;
-            println!("Error {}: {}", code, e);
-            return Err("sched_getparam fails".into());
-        }
+        0 => verbose!("getparam reports prio {}", params.sched_priority),
+        _ => return Err(RtError::last("sched_getparam")),
     };
     Ok(())
 }
-fn setscheduler(prio: i32, policy: Policy) -> Result<(), Box<dyn Error>> {
+fn setscheduler(prio: i32, policy: Policy) -> Result<(), RtError> {
     //! Set our prority, will fail if we request a real time prio and policy
     //! without root rights.
     //
@@ -238,7 +598,13 @@ fn setscheduler(prio: i32, policy: Policy) -> Result<(), Box<dyn Error>> {
     // https://docs.rs/libc/0.2.153/libc/fn.sched_setscheduler.html
 
     //getscheduler()?;
-    println!("Setting policy to {:?} and prio to {}", policy, prio);
+    if policy == Policy::Deadline {
+        return Err(RtError::Invalid {
+            call: "sched_setscheduler",
+            reason: "SCHED_DEADLINE needs setscheduler_deadline",
+        });
+    }
+    verbose!("Setting policy to {:?} and prio to {}", policy, prio);
     let pid: libc::c_int = 0;
     let libcpolicy = policy as libc::c_int;
     let params = libc::sched_param {
@@ -247,12 +613,7 @@ fn setscheduler(prio: i32, policy: Policy) -> Result<(), Box<dyn Error>> {
 
     match unsafe { libc::sched_setscheduler(pid, libcpolicy, &params) } {
         0 => (),
-        _ => {
-            let e = errno();
-            let code = e.0;
-            println!("Error {}: {}", code, e);
-            return Err("sched_setscheduler fails".into());
-        }
+        _ => return Err(RtError::last("sched_setscheduler")),
     };
 
     getschedparam()?;
@@ -264,15 +625,91 @@ fn setscheduler(prio: i32, policy: Policy) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn sleep_clock_nanosleep(sleep_ns: u32) {
-    //let clockid : libc::clockid_t = libc::CLOCK_REALTIME;
-    let clockid: libc::clockid_t = libc::CLOCK_MONOTONIC;
+/// Argument of sched_setattr, see include/uapi/linux/sched/types.h
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SchedAttr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+}
 
-    let flags: libc::c_int = libc::CLOCK_REALTIME; // 1: libc::TIMER_ABSTIME
-    let request = libc::timespec {
-        tv_sec: 0,
-        tv_nsec: sleep_ns as i64,
+fn setscheduler_deadline(runtime_ns: u64, deadline_ns: u64, period_ns: u64) -> Result<(), RtError> {
+    //! Switch the calling thread to SCHED_DEADLINE
+    //!
+    //! There is no libc wrapper for sched_setattr, so we need the raw syscall.
+    //! Will fail without root rights or when the affinity of the thread is
+    //! restricted to less than the whole root domain.
+    //
+    // https://manpages.debian.org/bookworm/manpages-dev/sched_setattr.2.en.html
+    // https://www.kernel.org/doc/html/latest/scheduler/sched-deadline.html
+
+    verbose!(
+        "Setting policy to Deadline with runtime {} ns, deadline {} ns, period {} ns",
+        runtime_ns,
+        deadline_ns,
+        period_ns
+    );
+    let attr = SchedAttr {
+        size: mem::size_of::<SchedAttr>() as u32,
+        sched_policy: libc::SCHED_DEADLINE as u32,
+        sched_runtime: runtime_ns,
+        sched_deadline: deadline_ns,
+        sched_period: period_ns,
+        ..Default::default()
+    };
+    let pid: libc::pid_t = 0;
+    let flags: libc::c_uint = 0;
+    match unsafe { libc::syscall(libc::SYS_sched_setattr, pid, &attr, flags) } {
+        0 => (),
+        _ => return Err(RtError::last("sched_setattr")),
     };
+
+    getscheduler()?;
+
+    Ok(())
+}
+
+/// Clock for sleeping and for the timestamps of the measurement
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Clock {
+    Monotonic,
+    Realtime,
+    /// International atomic time, e.g. for PTP/TSN synchronised systems
+    Tai,
+    /// Like monotonic, but also counts the time in suspend
+    Boottime,
+}
+
+impl Clock {
+    pub fn clockid(self) -> libc::clockid_t {
+        match self {
+            Clock::Monotonic => libc::CLOCK_MONOTONIC,
+            Clock::Realtime => libc::CLOCK_REALTIME,
+            Clock::Tai => libc::CLOCK_TAI,
+            Clock::Boottime => libc::CLOCK_BOOTTIME,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Clock::Monotonic => "CLOCK_MONOTONIC",
+            Clock::Realtime => "CLOCK_REALTIME",
+            Clock::Tai => "CLOCK_TAI",
+            Clock::Boottime => "CLOCK_BOOTTIME",
+        }
+    }
+}
+
+fn sleep_clock_nanosleep(clockid: libc::clockid_t, sleep_ns: u64) {
+    //! Sleep for a relative interval
+    let flags: libc::c_int = 0; // relative, see sleep_clock_nanosleep_abs
+    let request = to_timespec(sleep_ns);
     let mut remain = libc::timespec {
         tv_sec: 0,
         tv_nsec: 0,
@@ -283,76 +720,118 @@ fn sleep_clock_nanosleep(sleep_ns: u32) {
         ret = libc::clock_nanosleep(clockid, flags, &request, premain);
     }
     if ret != 0 {
-        println!("clock_nanosleep fails");
+        verbose!("clock_nanosleep fails");
     }
 }
 
-fn sample_sleep_with_duration(samples: u32, wait_time_ns: u32) -> Result<(), Box<dyn Error>> {
-    //! Messure latency of sleep with time::Duration
+fn sleep_clock_nanosleep_abs(clockid: libc::clockid_t, deadline: &Timespec) {
+    //! Sleep until the absolute deadline is reached (TIMER_ABSTIME)
+    let flags: libc::c_int = libc::TIMER_ABSTIME;
+    let request = libc::timespec {
+        tv_sec: deadline.sec,
+        tv_nsec: deadline.nsec,
+    };
+    let ret;
+    unsafe {
+        // The remaining time is not written for absolute sleeps
+        ret = libc::clock_nanosleep(clockid, flags, &request, std::ptr::null_mut());
+    }
+    if ret != 0 {
+        verbose!("clock_nanosleep fails");
+    }
+}
 
-    let sleep_time = Duration::from_nanos(wait_time_ns.into());
-    let mut diff: Duration;
-    let mut accumulator: Duration = Duration::new(0, 0);
-    let mut max_diff: Duration = Duration::new(0, 0);
-    for _s in 0..samples {
+fn sample_sleep_with_duration(stats: Arc<Stats>, param: ThreadParam) -> Result<(), RtError> {
+    //! Messure latency of std::thread::sleep with time::Duration
+    //!
+    //! The naive way to sleep in Rust, to compare it with clock_nanosleep.
+
+    let sleep_time = Duration::from_nanos(param.interval);
+
+    let mut cycle: u64 = 0;
+    while (param.cycles == 0 || cycle < param.cycles) && !param.stop.load(Ordering::Relaxed) {
+        cycle += 1;
         let start = Instant::now();
         thread::sleep(sleep_time);
         let end = Instant::now();
-        diff = end - start;
-        accumulator += diff;
-        if diff > max_diff {
-            max_diff = diff;
-        }
+        // An early return, e.g. on an error of the sleep, counts as 0
+        let latency = (end - start).saturating_sub(sleep_time);
+        let now = clock_gettime(param.clock.clockid());
+        account(&stats, &param, latency.as_nanos() as u64, None, now, cycle);
     }
-    let average_latency = accumulator / samples - sleep_time;
-    let max_latency = max_diff - sleep_time;
-    println!(
-        "Average Latency {:?} Maximal Latency {:?}",
-        average_latency, max_latency
-    );
     Ok(())
 }
 
-fn sample_clock_nanosleep_with_duration(stats: Arc<Mutex<Stats>>, param: ThreadParam) {
+fn sample_clock_nanosleep_with_duration(
+    stats: Arc<Stats>,
+    param: ThreadParam,
+) -> Result<(), RtError> {
     //! Messure latency of clock_nanosleep with time::Duration
 
-    let sleep_time = Duration::from_nanos(param.interval as u64);
-    let mut latency: Duration;
-    let mut accumulator: Duration = Duration::new(0, 0);
-    let mut max_latency = Duration::new(0, 0);
-    let mut min_latency = Duration::MAX;
+    let sleep_time = Duration::from_nanos(param.interval);
+    let mut sleeper = Sleeper::new(
+        param.sleep_backend,
+        param.clock.clockid(),
+        param.spin_margin,
+    )?;
 
     //setscheduler(99, Policy::Fifo).expect("setscheduler fails");
     //setaffinity(param.thread_num as u64).expect("setaffinity fails");
 
-    for _s in 0..param.cycles {
+    let mut cycle: u64 = 0;
+    while (param.cycles == 0 || cycle < param.cycles) && !param.stop.load(Ordering::Relaxed) {
+        cycle += 1;
         //TODO also check absolute time
         let start = Instant::now();
         //sleep_clock_nanosleep(1_000_000);
-        (param.sleep_fn)(param.interval);
+        let wakeup = sleeper.sleep(param.interval);
         let end = Instant::now();
-        latency = end - start - sleep_time;
-        accumulator += latency;
-        if latency > max_latency {
-            max_latency = latency;
-        }
-        if latency < min_latency {
-            min_latency = latency;
-        }
-        let mut stat = stats.lock().unwrap();
-        stat.threads[param.thread_num as usize].max = max_latency.as_nanos() as u64;
-        stat.threads[param.thread_num as usize].min = min_latency.as_nanos() as u64;
-        stat.threads[param.thread_num as usize].average =
-            (accumulator.as_nanos() as u64) / (param.cycles as u64);
-        let latency_us = latency.as_micros();
-        if latency_us < param.hist_size.try_into().unwrap() {
-            stat.threads[param.thread_num as usize].hist[latency_us as usize] += 1;
-        } else {
-            stat.threads[param.thread_num as usize].overflows += 1;
+        let latency = (end - start).saturating_sub(sleep_time).as_nanos() as u64;
+        let now = clock_gettime(param.clock.clockid());
+        account(&stats, &param, latency, wakeup, now, cycle);
+    }
+    Ok(())
+}
+
+fn account(
+    stats: &Stats,
+    param: &ThreadParam,
+    latency_ns: u64,
+    wakeup: Option<Wakeup>,
+    now: Timespec,
+    cycle: u64,
+) {
+    //! Check the thresholds and record the latency of a cycle
+    if let Some(bt) = &param.breaktrace {
+        if bt.check(param.thread_num, cycle, latency_ns) {
+            param.stop.store(true, Ordering::Relaxed);
         }
     }
+    if let Some(threshold) = &param.threshold {
+        if latency_ns > threshold.latency_ns {
+            (threshold.callback)(BreakInfo {
+                thread_num: param.thread_num,
+                cycle,
+                latency_ns,
+            });
+        }
+    }
+    let thread = &stats.threads[param.thread_num as usize];
+    thread.record(latency_ns, cycle);
+    thread.samples.push(Sample {
+        cycle,
+        wakeup_ns: now.as_ns(),
+        latency_ns,
+    });
+    if let Some(wakeup) = wakeup {
+        thread.record_wakeup(wakeup);
+    }
+    if param.rusage_interval > 0 && cycle.is_multiple_of(param.rusage_interval) {
+        thread.sample_rusage(Rusage::thread());
+    }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 struct Timespec {
     sec: i64,
     nsec: i64,
@@ -364,16 +843,43 @@ impl Timespec {
         let diff_s = (end.sec - begin.sec) * 1_000_000_000;
         end.nsec - begin.nsec + diff_s
     }
+
+    pub fn add_ns(self, ns: u64) -> Timespec {
+        //! Returns a new normalized Timespec that is ns nanoseconds later
+        let nsec = self.nsec + (ns % 1_000_000_000) as i64;
+        let sec = self.sec + (ns / 1_000_000_000) as i64;
+        Timespec {
+            sec: sec + nsec / 1_000_000_000,
+            nsec: nsec % 1_000_000_000,
+        }
+    }
+
+    pub fn as_ns(self) -> u64 {
+        //! Returns the time in nanoseconds since the epoch of the clock
+        (self.sec * 1_000_000_000 + self.nsec) as u64
+    }
+
+    pub fn sub_ns(self, ns: u64) -> Timespec {
+        //! Returns a new normalized Timespec that is ns nanoseconds earlier
+        let nsec = self.nsec - (ns % 1_000_000_000) as i64;
+        let sec = self.sec - (ns / 1_000_000_000) as i64;
+        match nsec < 0 {
+            true => Timespec {
+                sec: sec - 1,
+                nsec: nsec + 1_000_000_000,
+            },
+            false => Timespec { sec, nsec },
+        }
+    }
 }
 
-fn clock_gettime() -> Timespec {
+fn clock_gettime(clockid: libc::clockid_t) -> Timespec {
     // https://docs.rs/libc/0.2.153/libc/fn.clock_gettime.html
 
     let mut timespec = libc::timespec {
         tv_sec: 0,
         tv_nsec: 0,
     };
-    let clockid: libc::clockid_t = libc::CLOCK_MONOTONIC;
     let ret;
 
     unsafe { ret = libc::clock_gettime(clockid, &mut timespec) }
@@ -386,205 +892,1210 @@ fn clock_gettime() -> Timespec {
     }
 }
 
-fn sample_clock_nanosleep_with_gettime(stats: Arc<Mutex<Stats>>, param: ThreadParam) {
-    //! Messure latency of sleep with time::Duration
-
-    let sleep_time: u64 = param.interval as u64;
+fn sample_clock_nanosleep_with_gettime(
+    stats: Arc<Stats>,
+    param: ThreadParam,
+) -> Result<(), RtError> {
+    //! Messure latency of clock_nanosleep with clock_gettime
+    //!
+    //! With TimerMode::Absolute we keep a running deadline and sleep with
+    //! TIMER_ABSTIME, the latency is measured against the intended wakeup
+    //! time like the original cyclictest does. With TimerMode::Relative
+    //! the loop body adds up to the period. Both work with all sleep
+    //! backends, see Sleeper::sleep_until.
+
+    let sleep_time: u64 = param.interval;
     let mut latency: u64;
-    let mut accumulator: u64 = 0; //probably not the right type here
-    let mut max_latency: u64 = 0;
-    let mut min_latency: u64 = u64::MAX;
 
-    for _s in 0..param.cycles {
-        //TODO also check absolute time
-        let start = clock_gettime();
-        //sleep_clock_nanosleep(1_000_000);
-        (param.sleep_fn)(param.interval);
-        let end = clock_gettime();
-        latency = Timespec::diff_ns(start, end) as u64; // - sleep_time;
-        latency -= sleep_time;
-
-        accumulator += latency;
-        if latency > max_latency {
-            max_latency = latency;
-        }
-        if latency < min_latency {
-            min_latency = latency;
-        }
-        let mut stat = stats.lock().unwrap();
-        stat.threads[param.thread_num as usize].max = max_latency;
-        stat.threads[param.thread_num as usize].min = min_latency;
-        stat.threads[param.thread_num as usize].average = (accumulator) / (param.cycles as u64);
-        let latency_us = latency / 1000u64;
-        if latency_us < param.hist_size.try_into().unwrap() {
-            stat.threads[param.thread_num as usize].hist[latency_us as usize] += 1;
-        } else {
-            stat.threads[param.thread_num as usize].overflows += 1;
+    let clockid = param.clock.clockid();
+    let mut sleeper = Sleeper::new(param.sleep_backend, clockid, param.spin_margin)?;
+    let mut next = clock_gettime(clockid).add_ns(sleep_time);
+
+    let mut cycle: u64 = 0;
+    while (param.cycles == 0 || cycle < param.cycles) && !param.stop.load(Ordering::Relaxed) {
+        cycle += 1;
+        let wakeup;
+        let now;
+        match param.timer_mode {
+            TimerMode::Absolute => {
+                wakeup = sleeper.sleep_until(&next);
+                now = clock_gettime(clockid);
+                // Wakeups before the deadline are not expected, count them as 0
+                latency = Timespec::diff_ns(next, now).max(0) as u64;
+                next = next.add_ns(sleep_time);
+                // Skip periods that we have missed entirely
+                let mut overruns = 0;
+                while Timespec::diff_ns(next, now) > 0 {
+                    next = next.add_ns(sleep_time);
+                    overruns += 1;
+                }
+                if overruns > 0 {
+                    stats.threads[param.thread_num as usize].add_overruns(overruns);
+                }
+            }
+            TimerMode::Relative => {
+                let start = clock_gettime(clockid);
+                //sleep_clock_nanosleep(1_000_000);
+                wakeup = sleeper.sleep(sleep_time);
+                now = clock_gettime(clockid);
+                latency = Timespec::diff_ns(start, now) as u64; // - sleep_time;
+                latency = latency.saturating_sub(sleep_time);
+            }
         }
+
+        account(&stats, &param, latency, wakeup, now, cycle);
     }
+    Ok(())
 }
 
-pub fn run_with_sleep(num_threads: usize, _hist_size: usize) -> Result<(), Box<dyn Error>> {
-    println!("Starting measurement cycle ...");
-    for _i in 0..num_threads {
-        sample_sleep_with_duration(1000, 1_000_000)?;
+fn sample_posix_timer(stats: Arc<Stats>, param: ThreadParam) -> Result<(), RtError> {
+    //! Messure latency of a periodic POSIX timer that signals the thread
+    //!
+    //! Like the default mode of cyclictest: the timer is directed to this
+    //! thread with SIGEV_THREAD_ID and we wait for SIGALRM with sigwait.
+    //! SIGALRM must be blocked, see block_alarm. The timer runs on absolute
+    //! expiry times, TimerMode is ignored.
+    //
+    // https://manpages.debian.org/bookworm/manpages-dev/timer_create.2.en.html
+    // https://manpages.debian.org/bookworm/manpages-dev/sigwait.3.en.html
+
+    let interval: u64 = param.interval;
+    let clockid = param.clock.clockid();
+
+    let mut sev: libc::sigevent = unsafe { mem::zeroed() };
+    sev.sigev_notify = libc::SIGEV_THREAD_ID;
+    sev.sigev_signo = libc::SIGALRM;
+    sev.sigev_notify_thread_id = unsafe { libc::gettid() };
+    let mut timer: libc::timer_t = std::ptr::null_mut();
+    if unsafe { libc::timer_create(clockid, &mut sev, &mut timer) } != 0 {
+        return Err(RtError::last("timer_create"));
+    }
+
+    let mut sigset: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut sigset);
+        libc::sigaddset(&mut sigset, libc::SIGALRM);
+    }
+
+    let mut next = clock_gettime(clockid).add_ns(interval);
+    let spec = libc::itimerspec {
+        it_interval: libc::timespec {
+            tv_sec: (interval / 1_000_000_000) as i64,
+            tv_nsec: (interval % 1_000_000_000) as i64,
+        },
+        it_value: libc::timespec {
+            tv_sec: next.sec,
+            tv_nsec: next.nsec,
+        },
+    };
+    let flags = libc::TIMER_ABSTIME;
+    if unsafe { libc::timer_settime(timer, flags, &spec, std::ptr::null_mut()) } != 0 {
+        let e = RtError::last("timer_settime");
+        unsafe { libc::timer_delete(timer) };
+        return Err(e);
+    }
+
+    let mut cycle: u64 = 0;
+    while (param.cycles == 0 || cycle < param.cycles) && !param.stop.load(Ordering::Relaxed) {
+        cycle += 1;
+        let mut signum: libc::c_int = 0;
+        if unsafe { libc::sigwait(&sigset, &mut signum) } != 0 {
+            verbose!("sigwait fails");
+        }
+        let now = clock_gettime(clockid);
+        let latency = Timespec::diff_ns(next, now).max(0) as u64;
+        account(&stats, &param, latency, None, now, cycle);
+
+        // Expirations that were missed while the signal was pending
+        let overruns = unsafe { libc::timer_getoverrun(timer) }.max(0) as u64;
+        if overruns > 0 {
+            stats.threads[param.thread_num as usize].add_overruns(overruns);
+        }
+        next = next.add_ns(interval * (1 + overruns));
+    }
+
+    unsafe { libc::timer_delete(timer) };
+    Ok(())
+}
+
+fn sample_timerfd(stats: Arc<Stats>, param: ThreadParam) -> Result<(), RtError> {
+    //! Messure latency of a periodic timerfd, woken up by a blocking read
+    //!
+    //! The read returns the number of expirations since the last read, all
+    //! but the first one are counted as overruns. The timer runs on absolute
+    //! expiry times, TimerMode is ignored. CLOCK_TAI is not supported by
+    //! timerfd_create.
+    //
+    // https://manpages.debian.org/bookworm/manpages-dev/timerfd_create.2.en.html
+
+    let interval: u64 = param.interval;
+    let clockid = param.clock.clockid();
+
+    let fd = unsafe { libc::timerfd_create(clockid, libc::TFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(RtError::last("timerfd_create"));
+    }
+    // Closes the timerfd when we return
+    let _timerfd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut next = clock_gettime(clockid).add_ns(interval);
+    let spec = libc::itimerspec {
+        it_interval: libc::timespec {
+            tv_sec: (interval / 1_000_000_000) as i64,
+            tv_nsec: (interval % 1_000_000_000) as i64,
+        },
+        it_value: libc::timespec {
+            tv_sec: next.sec,
+            tv_nsec: next.nsec,
+        },
+    };
+    let flags = libc::TFD_TIMER_ABSTIME;
+    if unsafe { libc::timerfd_settime(fd, flags, &spec, std::ptr::null_mut()) } != 0 {
+        return Err(RtError::last("timerfd_settime"));
+    }
+
+    let mut buf = [0u8; 8];
+    let mut cycle: u64 = 0;
+    while (param.cycles == 0 || cycle < param.cycles) && !param.stop.load(Ordering::Relaxed) {
+        cycle += 1;
+        let len = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if len != buf.len() as isize {
+            return Err(RtError::last("read"));
+        }
+        let now = clock_gettime(clockid);
+        let expirations = u64::from_ne_bytes(buf);
+        let latency = Timespec::diff_ns(next, now).max(0) as u64;
+        account(&stats, &param, latency, None, now, cycle);
+
+        if expirations > 1 {
+            stats.threads[param.thread_num as usize].add_overruns(expirations - 1);
+        }
+        next = next.add_ns(interval * expirations.max(1));
     }
     Ok(())
 }
 
+/// How the wakeup time of a measurement thread is determined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerMode {
+    /// Sleep for the interval, starting from the end of the last cycle
+    Relative,
+    /// Sleep until a running deadline with TIMER_ABSTIME (cyclictest default)
+    Absolute,
+}
+
 struct ThreadParam {
     thread_num: u32,
-    interval: u32,
-    cycles: u32,
-    sleep_fn: fn(u32),
-    hist_size: usize,
+    /// Interval in ns
+    interval: u64,
+    /// Number of cycles, 0 means endless
+    cycles: u64,
+    priority: i32,
+    sleep_backend: SleepBackend,
+    /// Spin margin of SleepBackend::Spin in ns
+    spin_margin: u64,
+    clock: Clock,
+    timer_mode: TimerMode,
+    /// Finish the current cycle and return when set
+    stop: Arc<AtomicBool>,
+    breaktrace: Option<Arc<Breaktrace>>,
+    /// Callback of a Monitor
+    threshold: Option<Threshold>,
+    /// Bytes of stack to touch before the loop, 0 for none
+    prefault_stack: usize,
+    /// Sample getrusage every this many cycles, 0 for never
+    rusage_interval: u64,
 }
 
-#[derive(Clone)]
-struct ThreadStats {
-    //hist : [u32; hist_size],
-    hist: Vec<u32>,
-    overflows: u32,
-    average: u64,
-    max: u64,
-    min: u64,
+/// Results of a measurement thread, a snapshot of its SharedThreadStats
+///
+/// All latencies are in ns.
+#[derive(Clone, Debug)]
+pub struct ThreadStats {
+    pub hist: Vec<u32>,
+    /// Width of a histogram bucket
+    pub bucket_ns: u64,
+    pub overflows: u32,
+    /// Cycle numbers of the first overflows, at most as many as buckets
+    pub outliers: Vec<u64>,
+    /// Timer expirations that passed without a wakeup
+    pub overruns: u64,
+    /// Wakeup latency of the sleep part of SleepBackend::Spin
+    pub wakeup_sum: u64,
+    pub wakeup_max: u64,
+    pub wakeup_min: u64,
+    pub wakeups: u64,
+    /// Sleeps that returned after the deadline, the spin margin was too small
+    pub late_wakeups: u64,
+    /// Sum of all latencies for the average
+    pub sum: u64,
+    pub max: u64,
+    /// u64::MAX without any cycles
+    pub min: u64,
+    pub cycles: u64,
+    /// Page faults and context switches of the thread in the measurement loop
+    pub rusage: Rusage,
+    /// Rusage of the sample window of each outlier, None if not sampled
+    pub outlier_rusage: Vec<Option<Rusage>>,
+    /// Overflows in sample windows with page faults or involuntary switches,
+    /// unlike outlier_rusage this covers all overflows of the run
+    pub disturbed_overflows: u32,
+    /// perf counters of the measurement loop, all None unless enabled
+    pub perf: PerfCounters,
 }
 
 impl ThreadStats {
-    fn new(hist_size: usize) -> ThreadStats {
+    pub fn average(&self) -> u64 {
+        match self.cycles {
+            0 => 0,
+            cycles => self.sum / cycles,
+        }
+    }
+
+    pub fn wakeup_average(&self) -> u64 {
+        match self.wakeups {
+            0 => 0,
+            wakeups => self.wakeup_sum / wakeups,
+        }
+    }
+}
+
+/// Statistics of one measurement thread that can be read while it runs
+///
+/// Only the measurement thread writes, other threads take snapshots without
+/// ever blocking it. The alignment keeps the counters of different threads
+/// out of each other's cache lines.
+#[repr(align(64))]
+struct SharedThreadStats {
+    hist: Box<[AtomicU32]>,
+    /// Width of a histogram bucket in ns
+    bucket_ns: u64,
+    overflows: AtomicU32,
+    /// Cycle numbers of the first overflows, like cyclictest as many as buckets
+    outliers: Box<[AtomicU64]>,
+    overruns: AtomicU64,
+    wakeup_sum: AtomicU64,
+    wakeup_max: AtomicU64,
+    wakeup_min: AtomicU64,
+    wakeups: AtomicU64,
+    late_wakeups: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+    min: AtomicU64,
+    cycles: AtomicU64,
+    rusage: AtomicRusage,
+    /// Rusage at the last sample
+    rusage_sample: AtomicRusage,
+    outlier_rusage: Box<[AtomicRusage]>,
+    /// Outliers that have their outlier_rusage set
+    outliers_sampled: AtomicU32,
+    /// Overflows up to the last sample, all of them and not only outliers
+    overflows_sampled: AtomicU32,
+    disturbed_overflows: AtomicU32,
+    perf: AtomicPerfCounters,
+    /// Raw samples, empty unless the run records them
+    samples: SampleRing,
+}
+
+impl SharedThreadStats {
+    fn new(hist_size: usize, bucket_ns: u64) -> SharedThreadStats {
+        SharedThreadStats {
+            hist: (0..hist_size).map(|_| AtomicU32::new(0)).collect(),
+            bucket_ns,
+            overflows: AtomicU32::new(0),
+            outliers: (0..hist_size).map(|_| AtomicU64::new(0)).collect(),
+            overruns: AtomicU64::new(0),
+            wakeup_sum: AtomicU64::new(0),
+            wakeup_max: AtomicU64::new(0),
+            wakeup_min: AtomicU64::new(u64::MAX),
+            wakeups: AtomicU64::new(0),
+            late_wakeups: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            cycles: AtomicU64::new(0),
+            rusage: AtomicRusage::default(),
+            rusage_sample: AtomicRusage::default(),
+            outlier_rusage: (0..hist_size).map(|_| AtomicRusage::default()).collect(),
+            outliers_sampled: AtomicU32::new(0),
+            overflows_sampled: AtomicU32::new(0),
+            disturbed_overflows: AtomicU32::new(0),
+            perf: AtomicPerfCounters::default(),
+            samples: SampleRing::new(0),
+        }
+    }
+
+    fn record(&self, latency_ns: u64, cycle: u64) {
+        //! Account one latency, does not allocate or lock
+        //!
+        //! The counters are updated before cycles, so a snapshot never sees
+        //! more cycles than histogram entries.
+        self.sum.fetch_add(latency_ns, Ordering::Relaxed);
+        self.max.fetch_max(latency_ns, Ordering::Relaxed);
+        self.min.fetch_min(latency_ns, Ordering::Relaxed);
+        let bucket = latency_ns / self.bucket_ns;
+        if bucket < self.hist.len() as u64 {
+            self.hist[bucket as usize].fetch_add(1, Ordering::Relaxed);
+        } else {
+            let overflows = self.overflows.load(Ordering::Relaxed) as usize;
+            if overflows < self.outliers.len() {
+                self.outliers[overflows].store(cycle, Ordering::Relaxed);
+            }
+            self.overflows.fetch_add(1, Ordering::Release);
+        }
+        self.cycles.fetch_add(1, Ordering::Release);
+    }
+
+    fn record_wakeup(&self, wakeup: Wakeup) {
+        //! Account the sleep part of a spin wakeup
+        self.wakeup_sum
+            .fetch_add(wakeup.latency_ns, Ordering::Relaxed);
+        self.wakeup_max
+            .fetch_max(wakeup.latency_ns, Ordering::Relaxed);
+        self.wakeup_min
+            .fetch_min(wakeup.latency_ns, Ordering::Relaxed);
+        if wakeup.late {
+            self.late_wakeups.fetch_add(1, Ordering::Relaxed);
+        }
+        self.wakeups.fetch_add(1, Ordering::Release);
+    }
+
+    fn add_overruns(&self, overruns: u64) {
+        self.overruns.fetch_add(overruns, Ordering::Relaxed);
+    }
+
+    fn set_rusage(&self, rusage: Rusage) {
+        //! Totals of the measurement loop
+        self.rusage.store(rusage);
+    }
+
+    fn set_perf(&self, counters: PerfCounters) {
+        self.perf.store(counters);
+    }
+
+    fn start_rusage(&self, start: Rusage) {
+        //! Begin the first sample window
+        self.rusage_sample.store(start);
+    }
+
+    fn sample_rusage(&self, now: Rusage) {
+        //! Attribute the rusage since the last sample to the outliers
+        //! recorded since then
+        let delta = now.since(&self.rusage_sample.load());
+        self.rusage_sample.store(now);
+        let overflows = self.overflows.load(Ordering::Relaxed) as usize;
+        let outliers = overflows.min(self.outlier_rusage.len());
+        let sampled = self.outliers_sampled.load(Ordering::Relaxed) as usize;
+        for outlier in &self.outlier_rusage[sampled..outliers] {
+            outlier.store(delta);
+        }
+        self.outliers_sampled
+            .store(outliers as u32, Ordering::Release);
+        let since_sample = overflows as u32 - self.overflows_sampled.load(Ordering::Relaxed);
+        self.overflows_sampled
+            .store(overflows as u32, Ordering::Relaxed);
+        if delta.disturbed() {
+            self.disturbed_overflows
+                .fetch_add(since_sample, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> ThreadStats {
+        //! Copy the current values, can be called while the thread runs
+        //!
+        //! During a run the values are not taken at the same instant, e.g.
+        //! sum may already contain a latency that cycles does not count yet.
+        let cycles = self.cycles.load(Ordering::Acquire);
+        let wakeups = self.wakeups.load(Ordering::Acquire);
+        let overflows = self.overflows.load(Ordering::Acquire);
+        let outliers = self.outliers.iter().take(overflows as usize);
+        let sampled = self.outliers_sampled.load(Ordering::Acquire) as usize;
+        let outlier_rusage = self.outlier_rusage.iter().take(outliers.len());
         ThreadStats {
-            max: 0,
-            min: u64::MAX,
-            average: u64::MAX,
-            //hist: [0; hist_size],
-            hist: vec![0; hist_size],
-            overflows: 0,
+            hist: self
+                .hist
+                .iter()
+                .map(|h| h.load(Ordering::Relaxed))
+                .collect(),
+            bucket_ns: self.bucket_ns,
+            overflows,
+            outliers: outliers.map(|o| o.load(Ordering::Relaxed)).collect(),
+            overruns: self.overruns.load(Ordering::Relaxed),
+            wakeup_sum: self.wakeup_sum.load(Ordering::Relaxed),
+            wakeup_max: self.wakeup_max.load(Ordering::Relaxed),
+            wakeup_min: self.wakeup_min.load(Ordering::Relaxed),
+            wakeups,
+            late_wakeups: self.late_wakeups.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+            min: self.min.load(Ordering::Relaxed),
+            cycles,
+            rusage: self.rusage.load(),
+            outlier_rusage: outlier_rusage
+                .enumerate()
+                .map(|(i, rusage)| (i < sampled).then(|| rusage.load()))
+                .collect(),
+            disturbed_overflows: self.disturbed_overflows.load(Ordering::Relaxed),
+            perf: self.perf.load(),
         }
     }
 }
 
 struct Stats {
-    //threads: [ThreadStats; num_threads],
-    threads: Vec<ThreadStats>,
+    threads: Vec<SharedThreadStats>,
 }
 
 impl Stats {
-    fn new(num_threads: usize, hist_size: usize) -> Stats {
+    fn new(num_threads: usize, hist_size: usize, bucket_ns: u64) -> Stats {
         Stats {
-            threads: vec![ThreadStats::new(hist_size); num_threads],
+            threads: (0..num_threads)
+                .map(|_| SharedThreadStats::new(hist_size, bucket_ns))
+                .collect(),
+        }
+    }
+
+    fn record_samples(mut self, capacity: usize) -> Stats {
+        //! Record the last capacity samples of each thread
+        for thread in &mut self.threads {
+            thread.samples = SampleRing::new(capacity);
         }
+        self
+    }
+
+    fn snapshot(&self) -> Vec<ThreadStats> {
+        self.threads.iter().map(|t| t.snapshot()).collect()
+    }
+
+    fn samples(&self) -> Vec<Vec<Sample>> {
+        self.threads.iter().map(|t| t.samples.samples()).collect()
     }
 }
 
-pub fn run_measurement(
-    measurement: MeasurementType,
-    num_threads: usize,
-    hist_size: usize,
-    distance: u32,
-) -> Result<(), Box<dyn Error>> {
-    mlockall()?;
+fn setup_thread(
+    param: &ThreadParam,
+    cpu: Option<usize>,
+    policy: Policy,
+    deadline: Option<DeadlineParam>,
+) -> Result<(), RtError> {
+    //! Prepare the calling measurement thread, the affinity is set first
+    block_stop_signals()?;
+    if let Some(cpu) = cpu {
+        setaffinity(cpu as u64)?;
+    }
+    match (policy, deadline) {
+        (Policy::Deadline, Some(dl)) => {
+            let period_us = dl.period_us.unwrap_or((param.interval / 1_000) as u32);
+            let deadline_us = dl.deadline_us.unwrap_or(period_us);
+            setscheduler_deadline(
+                dl.runtime_us as u64 * 1_000,
+                deadline_us as u64 * 1_000,
+                period_us as u64 * 1_000,
+            )
+        }
+        _ => setscheduler(param.priority, policy),
+    }?;
+    if param.prefault_stack > 0 {
+        memory::prefault_stack(param.prefault_stack);
+    }
+    Ok(())
+}
+
+/// Results of a measurement run
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub measurement: MeasurementType,
+    pub clock: Clock,
+    /// Local time like in the rt-tests JSON, e.g. 2024-02-01T12:00:00
+    pub start_time: String,
+    pub end_time: String,
+    /// One entry per measurement thread
+    pub threads: Vec<ThreadStats>,
+    /// Where the breaktrace threshold was exceeded first
+    pub breaktrace: Option<BreakInfo>,
+    /// The missing privileges of a degraded run under SCHED_OTHER without
+    /// mlockall, empty for a real-time run
+    pub degraded: Vec<&'static str>,
+    /// /dev/cpu_dma_latency was held at 0 to keep the CPUs out of deep
+    /// C-states, false if it could not be written
+    pub latency_target: bool,
+    /// The recorded samples of each thread, oldest first
+    pub samples: Vec<Vec<Sample>>,
+}
+
+pub fn run(config: &MeasurementConfig) -> Result<Report, Box<dyn Error>> {
+    //! Run a measurement and return the results, prints nothing unless
+    //! the config is verbose
+    //!
+    //! The signal handlers of the process are left alone, only the command
+    //! line stops the run on SIGINT and SIGTERM.
+    VERBOSE.store(config.verbose, Ordering::Relaxed);
+    config.validate()?;
+    // A signal of an earlier run must not stop this one
+    STOP_REQUESTED.store(false, Ordering::SeqCst);
+    let degraded = Privileges::detect().missing(config);
+    let config = &match (degraded.is_empty(), config.allow_degraded) {
+        (true, _) => config.clone(),
+        (false, true) => {
+            verbose!("Degraded run, missing {}", degraded.join(", "));
+            config.clone().degraded()
+        }
+        (false, false) => {
+            return Err(format!(
+                "Missing {}; run as root, grant the capabilities or allow a degraded run (--allow-degraded)",
+                degraded.join(", ")
+            )
+            .into())
+        }
+    };
+    if config.prefault {
+        verbose!("Tuning malloc");
+        memory::tune_malloc()?;
+    }
+    if degraded.is_empty() {
+        mlockall()?;
+    }
+    if config.prefault && config.heap_reserve > 0 {
+        verbose!("Prefaulting {} KiB of heap", config.heap_reserve >> 10);
+        memory::reserve_heap(config.heap_reserve)?;
+    }
     //setscheduler(99, Policy::Fifo)?;
     //setaffinity(0)?;
     block_alarm()?;
 
     // We need to keep the file open to disable power management
-    let _file = set_latency_target()?;
-    let mut handles = vec![];
-    let stats_data = Stats::new(num_threads, hist_size);
-    let stats = Arc::new(Mutex::new(stats_data));
+    let latency_file = match set_latency_target() {
+        Ok(file) => Some(file),
+        Err(e) => {
+            verbose!("Warning: power management stays on, {}", e);
+            None
+        }
+    };
+    let latency_target = latency_file.is_some();
+    let num_threads = config.num_threads;
+    let policy = config.policy;
+    if policy == Policy::Deadline && config.deadline.is_none() {
+        return Err("SCHED_DEADLINE needs a runtime".into());
+    }
+    if let Some(cpus) = &config.cpus {
+        if policy == Policy::Deadline {
+            // see setscheduler_deadline
+            return Err("SCHED_DEADLINE threads can't be pinned to CPUs".into());
+        }
+        let online = online_cpus()?;
+        match cpus.iter().find(|cpu| !online.contains(cpu)) {
+            Some(cpu) => return Err(RtError::CpuNotOnline { cpu: *cpu }.into()),
+            None if cpus.is_empty() => return Err("Empty CPU list".into()),
+            None => (),
+        }
+    }
+    let stack_size = match (config.stack_size, config.prefault) {
+        (Some(size), _) if size < memory::MIN_STACK_SIZE => {
+            return Err(format!(
+                "Stack size needs at least {} KiB",
+                memory::MIN_STACK_SIZE >> 10
+            )
+            .into())
+        }
+        (Some(size), _) => Some(size),
+        // Prefault what the thread really has
+        (None, true) => Some(memory::DEFAULT_STACK_SIZE),
+        (None, false) => None,
+    };
+    let mut handles: Vec<thread::JoinHandle<Result<(), RtError>>> = vec![];
+    if config.record_samples > 0 {
+        verbose!(
+            "Allocating {} KiB for the samples",
+            (num_threads * config.record_samples * mem::size_of::<Sample>()) >> 10
+        );
+    }
+    let stats = Arc::new(
+        Stats::new(num_threads, config.hist_size, config.hist_bucket_ns)
+            .record_samples(config.record_samples),
+    );
+    let stop = Arc::new(AtomicBool::new(false));
+    let breaktrace = config.breaktrace_us.map(|us| Arc::new(Breaktrace::new(us)));
+    let measurement = config.measurement;
     let measurement_fn = match measurement {
+        MeasurementType::Sleep => sample_sleep_with_duration,
         MeasurementType::ClockNanosleep => sample_clock_nanosleep_with_duration,
         MeasurementType::ClockNanosleepGettime => sample_clock_nanosleep_with_gettime,
+        MeasurementType::PosixTimer => sample_posix_timer,
+        MeasurementType::Timerfd => sample_timerfd,
     };
-    println!("Starting measurement cycle ...");
+    let start_time = json::timestamp();
+    verbose!("Using clock {}", config.clock.name());
+    if let MeasurementType::ClockNanosleep | MeasurementType::ClockNanosleepGettime = measurement {
+        verbose!("Using sleep backend {}", config.sleep_backend.name());
+    }
+    if config.sleep_backend == SleepBackend::Spin {
+        verbose!("Spinning {} µs before the deadline", config.spin_margin_us);
+    }
+    verbose!("Starting measurement cycle ...");
     for thread in 0..num_threads {
         let stats = Arc::clone(&stats);
         let param = ThreadParam {
             thread_num: thread as u32,
-            interval: 1_000_000 + num_threads as u32 * distance * 1_000,
-            cycles: 10_000,
-            sleep_fn: sleep_clock_nanosleep,
-            //sleep_fn : thread::sleep,
-            hist_size,
+            interval: config.interval_ns(thread),
+            cycles: config.loops,
+            priority: config.thread_priority(thread),
+            sleep_backend: config.sleep_backend,
+            spin_margin: config.spin_margin_us as u64 * 1_000,
+            clock: config.clock,
+            timer_mode: config.timer_mode,
+            stop: Arc::clone(&stop),
+            breaktrace: breaktrace.clone(),
+            threshold: None,
+            prefault_stack: match (config.prefault, stack_size) {
+                (true, Some(size)) => size - memory::STACK_RESERVE,
+                _ => 0,
+            },
+            rusage_interval: config.rusage_interval,
         };
-        let handle = thread::spawn(move || {
-            let _ = setaffinity(thread as u64);
-            setscheduler(99, Policy::Fifo).expect("setscheduler fails");
-            measurement_fn(stats, param)
+        let cpu = config.cpus.as_ref().map(|cpus| cpus[thread % cpus.len()]);
+        let deadline = config.deadline;
+        let perf = config.perf;
+        let mut builder = thread::Builder::new();
+        if let Some(size) = stack_size {
+            builder = builder.stack_size(size);
+        }
+        let spawned = builder.spawn(move || -> Result<(), RtError> {
+            if let Err(e) = setup_thread(&param, cpu, policy, deadline) {
+                // Don't let the other threads measure for nothing
+                param.stop.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+            let sampling = param.rusage_interval > 0;
+            let start = Rusage::thread();
+            stats.threads[thread].start_rusage(start);
+            let counters = perf.then(perf::Counters::open);
+            if let Some(counters) = &counters {
+                counters.enable();
+            }
+            let result = measurement_fn(Arc::clone(&stats), param);
+            if let Some(counters) = &counters {
+                stats.threads[thread].set_perf(counters.read());
+            }
+            let end = Rusage::thread();
+            if sampling {
+                // The outliers of the last, incomplete window
+                stats.threads[thread].sample_rusage(end);
+            }
+            stats.threads[thread].set_rusage(end.since(&start));
+            result
         });
+        let handle = match spawned {
+            Ok(handle) => handle,
+            Err(e) => {
+                // The threads that are already running would never stop
+                stop.store(true, Ordering::Relaxed);
+                for handle in handles {
+                    let _ = handle.join();
+                }
+                return Err(format!("Thread {}: {}", thread, e).into());
+            }
+        };
 
         handles.push(handle);
     }
-    for handle in handles {
-        handle.join().unwrap()
+
+    // Wait for the threads, they finish their current cycle when we stop them
+    let start = Instant::now();
+    while !handles.iter().all(|h| h.is_finished()) {
+        if !stop.load(Ordering::Relaxed) {
+            if STOP_REQUESTED.load(Ordering::SeqCst) {
+                verbose!("Stopping measurement on signal ...");
+                stop.store(true, Ordering::Relaxed);
+            } else if config.duration.is_some_and(|d| start.elapsed() >= d) {
+                verbose!("Stopping measurement after {:?} ...", start.elapsed());
+                stop.store(true, Ordering::Relaxed);
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut result = Ok(());
+    for (thread_num, handle) in handles.into_iter().enumerate() {
+        let error = match handle.join() {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some("panicked".to_string()),
+        };
+        if let Some(e) = error {
+            let e = format!("Thread {}: {}", thread_num, e);
+            verbose!("{}", e);
+            result = result.and(Err(e));
+        }
     }
+    result?;
+
+    Ok(Report {
+        measurement,
+        clock: config.clock,
+        start_time,
+        end_time: json::timestamp(),
+        // All threads are joined, the snapshot is complete
+        threads: stats.snapshot(),
+        breaktrace: breaktrace.and_then(|bt| bt.info()),
+        degraded,
+        latency_target,
+        samples: stats.samples(),
+    })
+}
+
+/// Output options of the command line
+#[derive(Clone)]
+struct Output {
+    /// Report latencies in ns instead of µs
+    nsecs: bool,
+    /// Print the histogram in the format of cyclictest -h
+    upstream_hist: bool,
+    /// Write the results in the JSON format of rt-tests to this file
+    json: Option<PathBuf>,
+    /// Write the raw samples to this file, see record_samples
+    record: Option<PathBuf>,
+}
 
-    // Stats was moved to the Mutex, we just need to access it
-    let final_stats = stats.lock().unwrap();
-    println!("Histogram: Rows:Latency_us; Columns:Threads");
-    for h in 0..hist_size {
-        print!("{:2} ", h);
-        for i in 0..num_threads {
-            print!("{:5} ", final_stats.threads[i].hist[h]);
+impl Output {
+    fn suffixed(&self, name: &str) -> Output {
+        //! The output of one of several measurements, which must not
+        //! overwrite the files of the others
+        Output {
+            json: self.json.as_deref().map(|path| suffixed_path(path, name)),
+            record: self.record.as_deref().map(|path| suffixed_path(path, name)),
+            ..self.clone()
+        }
+    }
+}
+
+fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    //! results.json with the suffix timerfd becomes results-timerfd.json
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, suffix),
+    };
+    path.with_file_name(name)
+}
+
+/// Samples per thread of an endless run with --record, 24 MB
+const DEFAULT_RECORD_SIZE: usize = 1_000_000;
+
+fn run_measurement(config: &MeasurementConfig, output: &Output) -> Result<(), Box<dyn Error>> {
+    //! Run a measurement and print the results like cyclictest
+    let report = run(config)?;
+    let final_stats = &report.threads;
+    let (unit_ns, unit) = match output.nsecs {
+        true => (1, "ns"),
+        false => (1_000, "us"),
+    };
+    if output.upstream_hist {
+        histogram::write_histogram(&mut std::io::stdout(), final_stats, unit_ns)?;
+    } else {
+        println!("Histogram: Rows:Latency_{}; Columns:Threads", unit);
+        for h in 0..config.hist_size {
+            print!("{:2} ", h as u64 * config.hist_bucket_ns / unit_ns);
+            for thread in final_stats {
+                print!("{:5} ", thread.hist[h]);
+            }
+            println!();
+        }
+        print!("Ov ");
+        for thread in final_stats {
+            print!("{:5} ", thread.overflows);
         }
         println!();
     }
-    print!("Ov ");
-    for i in 0..num_threads {
-        print!("{:5} ", final_stats.threads[i].overflows);
+    println!("Stats ({})", config.clock.name());
+    for (i, thread) in final_stats.iter().enumerate() {
+        println!(
+            "T{} µs: Min {:6.1}  Avg {:6.1}  Max {:6.1}  Overflows {:6}  Overruns {:6}",
+            i,
+            thread.min as f64 / 1000f64,
+            thread.average() as f64 / 1000f64,
+            thread.max as f64 / 1000f64,
+            thread.overflows,
+            thread.overruns
+        );
+    }
+    if final_stats.iter().any(|t| t.wakeups > 0) {
+        // The stats above are the deadline accuracy after spinning
+        println!("Wakeups (deadline - {} µs)", config.spin_margin_us);
+        for (i, thread) in final_stats.iter().enumerate() {
+            let min = match thread.wakeups {
+                0 => 0,
+                _ => thread.wakeup_min,
+            };
+            println!(
+                "T{} µs: Min {:6.1}  Avg {:6.1}  Max {:6.1}  Late {:6}  Spin only {:6}",
+                i,
+                min as f64 / 1000f64,
+                thread.wakeup_average() as f64 / 1000f64,
+                thread.wakeup_max as f64 / 1000f64,
+                thread.late_wakeups,
+                thread.cycles - thread.wakeups
+            );
+        }
     }
-    println!("\nStats");
-    for i in 0..num_threads {
+    println!("Rusage of the measurement loops");
+    for (i, thread) in final_stats.iter().enumerate() {
+        let rusage = &thread.rusage;
         println!(
-            "T{} µs: Min {:6.1}  Avg {:6.1}  Max {:6.1}  Overflows {:6}",
+            "T{} Faults: Minor {:6}  Major {:6}  Switches: Voluntary {:8}  Involuntary {:6}",
             i,
-            final_stats.threads[i].min as f64 / 1000f64,
-            final_stats.threads[i].average as f64 / 1000f64,
-            final_stats.threads[i].max as f64 / 1000f64,
-            final_stats.threads[i].overflows
+            rusage.minor_faults,
+            rusage.major_faults,
+            rusage.voluntary_switches,
+            rusage.involuntary_switches
+        );
+    }
+    if config.perf {
+        println!("Perf counters of the measurement loops");
+        for (i, thread) in final_stats.iter().enumerate() {
+            let cpu = match &config.cpus {
+                Some(cpus) => format!("CPU {:3}", cpus[i % cpus.len()]),
+                None => "CPU   -".to_string(),
+            };
+            let count = |count: Option<u64>| count.map_or("n/a".to_string(), |c| c.to_string());
+            let perf = &thread.perf;
+            println!(
+                "T{} {}: Switches {:>6}  Migrations {:>4}  Faults {:>4}  Instructions {:>12}  Cache misses {:>8}",
+                i,
+                cpu,
+                count(perf.context_switches),
+                count(perf.cpu_migrations),
+                count(perf.page_faults),
+                count(perf.instructions),
+                count(perf.cache_misses)
+            );
+        }
+    }
+    if config.rusage_interval > 0 && final_stats.iter().any(|t| t.overflows > 0) {
+        println!(
+            "Spikes (rusage sampled every {} cycles)",
+            config.rusage_interval
         );
+        for (i, thread) in final_stats.iter().enumerate() {
+            for (cycle, rusage) in thread.outliers.iter().zip(&thread.outlier_rusage) {
+                if let Some(rusage) = rusage {
+                    // Each cycle sleeps, so voluntary switches are expected
+                    let rusage = Rusage {
+                        voluntary_switches: 0,
+                        ..*rusage
+                    };
+                    println!("T{} cycle {}: {}", i, cycle, rusage);
+                }
+            }
+            // The list above stops after the first outliers, this counts all
+            if thread.overflows > 0 {
+                println!(
+                    "T{} {} of {} spikes with page faults or involuntary switches",
+                    i, thread.disturbed_overflows, thread.overflows
+                );
+            }
+        }
+    }
+    if !report.degraded.is_empty() {
+        println!(
+            "# Degraded run, not real-time: missing {}",
+            report.degraded.join(", ")
+        );
+    }
+    if !report.latency_target {
+        println!("# Power management not disabled: /dev/cpu_dma_latency not written");
+    }
+    if let Some(info) = report.breaktrace {
+        println!("# Break thread: {}", info.thread_num);
+        println!("# Break cycle: {}", info.cycle);
+        println!("# Break value: {:.1} µs", info.latency_ns as f64 / 1000f64);
+    }
+    // The checks go into every result file
+    let checks = match output.json.is_some() || output.record.is_some() {
+        true => run_checks(),
+        false => vec![],
+    };
+    if let Some(path) = &output.json {
+        let sysinfo = json::SysInfo::new()?;
+        let cmdline: Vec<String> = std::env::args().collect();
+        let info = json::JsonInfo {
+            cmdline: &cmdline.join(" "),
+            start_time: &report.start_time,
+            end_time: &report.end_time,
+            return_code: 0,
+            nsecs: output.nsecs,
+            sysinfo: &sysinfo,
+            checks: &checks,
+            degraded: &report.degraded,
+            latency_target: report.latency_target,
+        };
+        // The parameters that were actually used
+        let config = match report.degraded.is_empty() {
+            true => config.clone(),
+            false => config.clone().degraded(),
+        };
+        let mut file = File::create(path)?;
+        json::write_json(&mut file, &info, &config, final_stats)?;
+        println!("Results written to {}", path.display());
+    }
+    if let Some(path) = &output.record {
+        let recording = Recording {
+            clockid: config.clock.clockid(),
+            checks,
+            threads: final_stats
+                .iter()
+                .zip(&report.samples)
+                .enumerate()
+                .map(|(i, (thread, samples))| ThreadRecord {
+                    cpu: config.cpus.as_ref().map(|cpus| cpus[i % cpus.len()]),
+                    interval_ns: config.interval_ns(i),
+                    cycles: thread.cycles,
+                    overruns: thread.overruns,
+                    min: thread.min,
+                    max: thread.max,
+                    sum: thread.sum,
+                    samples: samples.clone(),
+                })
+                .collect(),
+        };
+        let mut file = std::io::BufWriter::new(File::create(path)?);
+        write_recording(&mut file, &recording)?;
+        file.flush()?;
+        let count: usize = report.samples.iter().map(|s| s.len()).sum();
+        println!("{} samples written to {}", count, path.display());
     }
     Ok(())
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum MeasurementType {
+    /// std::thread::sleep for the interval
+    Sleep,
     ClockNanosleep,
     ClockNanosleepGettime,
+    /// Periodic timer_create timer, woken up with sigwait
+    PosixTimer,
+    /// Periodic timerfd, woken up by a blocking read
+    Timerfd,
+}
+
+fn run_check() -> Result<(), Box<dyn Error>> {
+    //! Print the preflight checks, fails if one of them fails
+    let checks = run_checks();
+    for check in &checks {
+        println!("{}", check);
+    }
+    match checks
+        .iter()
+        .filter(|c| c.status == CheckStatus::Fail)
+        .count()
+    {
+        0 => Ok(()),
+        failed => Err(format!("{} of {} checks failed", failed, checks.len()).into()),
+    }
+}
+
+fn run_analyze(args: &AnalyzeArgs) -> Result<(), Box<dyn Error>> {
+    //! Print the analysis of a recorded run
+    let mut file = std::io::BufReader::new(File::open(&args.file)?);
+    let recording =
+        read_recording(&mut file).map_err(|e| format!("{}: {}", args.file.display(), e))?;
+    let unit_ns = match args.nsecs {
+        true => 1,
+        false => 1_000,
+    };
+    let options = AnalyzeOptions {
+        hist_size: args.buckets,
+        bucket_ns: args.hist_width * unit_ns,
+        nsecs: args.nsecs,
+        threshold_ns: args.threshold.map(|us| us * 1_000),
+        spikes: args.spikes,
+        window_ns: args.window * 1_000,
+    };
+    analyze(&mut std::io::stdout().lock(), &recording, &options)?;
+    Ok(())
+}
+
+fn stop_requested() -> bool {
+    //! A signal stopped the last measurement, run() resets the flag so the
+    //! remaining ones have to be skipped here
+    let stop = STOP_REQUESTED.load(Ordering::SeqCst);
+    if stop {
+        println!("Skipping the remaining measurements on signal");
+    }
+    stop
 }
 
 pub fn cyclictest_main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
-    let hist_size: usize = 15;
+    match &args.command {
+        Some(Command::Check(_)) => return run_check(),
+        Some(Command::Analyze(analyze_args)) => return run_analyze(analyze_args),
+        None => (),
+    }
+
+    let (num_threads, cpus) = match (args.smp, args.affinity) {
+        (true, _) => {
+            let online = online_cpus()?;
+            (online.len(), Some(online))
+        }
+        (false, affinity) => {
+            let num_threads = match args.threads {
+                0 => thread::available_parallelism()?.get(),
+                n => n,
+            };
+            let cpus = match affinity {
+                None => None,
+                Some(Affinity::Online) => Some(online_cpus()?),
+                Some(Affinity::List(cpus)) => Some(cpus),
+            };
+            (num_threads, cpus)
+        }
+    };
+
+    let timer_mode = match args.relative {
+        true => TimerMode::Relative,
+        false => TimerMode::Absolute,
+    };
+
+    let policy = match (args.policy, args.priority) {
+        (Some(policy), 0) if policy.is_realtime() => {
+            return Err(format!("{:?} needs a priority of 1 to 99", policy).into())
+        }
+        (Some(policy), _) => policy,
+        (None, 0) => Policy::Other,
+        (None, _) => Policy::Fifo,
+    };
+    let deadline = args.dl_runtime.map(|runtime_us| DeadlineParam {
+        runtime_us,
+        deadline_us: args.dl_deadline,
+        period_us: args.dl_period,
+    });
+
+    let stack_size = args
+        .stack_size
+        .map(|kib| {
+            kib.checked_mul(1 << 10)
+                .ok_or_else(|| format!("Stack size of {} KiB is too large", kib))
+        })
+        .transpose()?;
+    let heap_reserve = args
+        .heap_reserve
+        .checked_mul(1 << 20)
+        .ok_or_else(|| format!("Heap reserve of {} MiB is too large", args.heap_reserve))?;
+
+    let config = MeasurementConfig {
+        num_threads,
+        interval_us: args.interval,
+        distance_us: args.distance,
+        loops: args.loops.unwrap_or(match args.duration {
+            Some(_) => 0,
+            None => 10_000,
+        }),
+        duration: args.duration,
+        priority: args.priority,
+        // Like cyclictest, all threads of a histogram or SMP run are equal
+        descending_priority: !(args.histogram.is_some() || args.smp),
+        policy,
+        deadline,
+        cpus,
+        breaktrace_us: args.breaktrace,
+        hist_size: args.histogram.unwrap_or(15),
+        hist_bucket_ns: match args.nsecs {
+            true => args.hist_width,
+            false => args.hist_width * 1_000,
+        },
+        timer_mode,
+        clock: args.clock,
+        spin_margin_us: args.spin_margin,
+        verbose: true,
+        allow_degraded: args.allow_degraded,
+        stack_size,
+        prefault: args.prefault,
+        heap_reserve,
+        rusage_interval: args.rusage,
+        perf: args.perf,
+        ..Default::default()
+    };
+    let config = match (&args.record, args.record_size) {
+        (None, _) => config,
+        (Some(_), Some(size)) => config.record_samples(size),
+        (Some(_), None) if config.loops > 0 => {
+            let loops = config.loops as usize;
+            config.record_samples(loops)
+        }
+        (Some(_), None) => config.record_samples(DEFAULT_RECORD_SIZE),
+    };
+    let output = Output {
+        nsecs: args.nsecs,
+        upstream_hist: args.histogram.is_some(),
+        json: args.json,
+        record: args.record,
+    };
 
-    let num_threads: usize = 12;
-    let distance_us: u32 = 500;
+    // With several measurements each one writes its own files
+    let measurements = args.sleep as usize
+        + (args.nanosleep as usize + args.nanosleepgettime as usize) * args.sleep_backend.len()
+        + args.posixtimer as usize
+        + args.timerfd as usize;
+    let output_of = |name: String| match measurements {
+        1 => output.clone(),
+        _ => output.suffixed(&name),
+    };
 
+    VERBOSE.store(true, Ordering::Relaxed);
     get_sched_get_priority_max()?;
+    // Ctrl-C stops the threads after their current cycle, see run()
+    install_stop_handler()?;
 
     if args.sleep {
-        println!("Testing with simple sleep");
-        run_with_sleep(num_threads, hist_size)?;
+        println!("Testing with std::thread::sleep");
+        let config = config.clone().measurement(MeasurementType::Sleep);
+        run_measurement(&config, &output_of("sleep".to_string()))?;
+        if stop_requested() {
+            return Ok(());
+        }
     }
 
-    if args.nanosleep {
-        println!("Testing with clock_nanosleep");
-        run_measurement(
-            MeasurementType::ClockNanosleep,
-            num_threads,
-            hist_size,
-            distance_us,
-        )?;
+    for backend in &args.sleep_backend {
+        let config = config.clone().sleep_backend(*backend);
+        let backend_name = backend.to_possible_value().unwrap();
+        let backend_name = backend_name.get_name();
+
+        if args.nanosleep {
+            println!("Testing with {}", backend.name());
+            let config = config.clone().measurement(MeasurementType::ClockNanosleep);
+            run_measurement(&config, &output_of(format!("nanosleep-{}", backend_name)))?;
+            if stop_requested() {
+                return Ok(());
+            }
+        }
+
+        if args.nanosleepgettime {
+            println!("Testing with {} and clock_gettime", backend.name());
+            let config = config.measurement(MeasurementType::ClockNanosleepGettime);
+            let name = format!("nanosleepgettime-{}", backend_name);
+            run_measurement(&config, &output_of(name))?;
+            if stop_requested() {
+                return Ok(());
+            }
+        }
     }
 
-    if args.nanosleepgettime {
-        println!("Testing with clock_nanosleep and clock_gettime");
-        run_measurement(
-            MeasurementType::ClockNanosleepGettime,
-            num_threads,
-            hist_size,
-            distance_us,
-        )?;
+    if args.posixtimer {
+        println!("Testing with POSIX timers and sigwait");
+        let config = config.clone().measurement(MeasurementType::PosixTimer);
+        run_measurement(&config, &output_of("posixtimer".to_string()))?;
+        if stop_requested() {
+            return Ok(());
+        }
+    }
+
+    if args.timerfd {
+        println!("Testing with timerfd");
+        let config = config.clone().measurement(MeasurementType::Timerfd);
+        run_measurement(&config, &output_of("timerfd".to_string()))?;
+        if stop_requested() {
+            return Ok(());
+        }
     }
 
     if args.benchmarks {
@@ -599,6 +2110,25 @@ pub fn cyclictest_main() -> Result<(), Box<dyn Error>> {
 mod test {
     use super::*;
 
+    fn test_param(cycles: u64, timer_mode: TimerMode) -> ThreadParam {
+        //! A thread with a 1 ms interval on clock_nanosleep and CLOCK_MONOTONIC
+        ThreadParam {
+            thread_num: 0,
+            interval: 1_000_000,
+            cycles,
+            priority: 0,
+            sleep_backend: SleepBackend::ClockNanosleep,
+            spin_margin: 0,
+            clock: Clock::Monotonic,
+            timer_mode,
+            stop: Arc::new(AtomicBool::new(false)),
+            breaktrace: None,
+            threshold: None,
+            prefault_stack: 0,
+            rusage_interval: 0,
+        }
+    }
+
     #[test]
     fn test_setaffinity() -> Result<(), Box<dyn Error>> {
         setaffinity(0)?;
@@ -609,14 +2139,38 @@ mod test {
     }
 
     #[test]
-    fn test_setaffinity_fail() -> Result<(), Box<dyn Error>> {
-        // TODO This doesn't look nice
-        // TODO Can we also check the error message?
+    fn test_setaffinity_fail() {
         let cpu = 99; // Will fail unless we have many cpus :)
-        match setaffinity(cpu) {
-            Ok(_) => Err("This should fail".into()),
-            Err(_) => Ok(()),
-        }
+        assert_eq!(setaffinity(cpu), Err(RtError::CpuNotOnline { cpu: 99 }));
+        assert_eq!(
+            setaffinity(100_000),
+            Err(RtError::CpuNotOnline { cpu: 100_000 })
+        );
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("3"), Ok(vec![3]));
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n"),
+            Ok(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("8,0-2,1"), Ok(vec![0, 1, 2, 8]));
+        assert_eq!(parse_cpu_list("0-7:2"), Ok(vec![0, 2, 4, 6]));
+    }
+
+    #[test]
+    fn test_parse_cpu_list_fail() {
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("0-3,a").is_err());
+        assert!(parse_cpu_list("0-3:0").is_err());
+    }
+
+    #[test]
+    fn test_online_cpus() -> Result<(), Box<dyn Error>> {
+        assert!(online_cpus()?.contains(&0));
+        Ok(())
     }
 
     #[test]
@@ -627,12 +2181,12 @@ mod test {
     }
 
     #[test]
-    fn test_block_alarm() -> Result<(), &'static str> {
+    fn test_block_alarm() -> Result<(), RtError> {
         block_alarm()
     }
 
     #[test]
-    fn test_mlockall() -> Result<(), Box<dyn Error>> {
+    fn test_mlockall() -> Result<(), RtError> {
         mlockall()
     }
 
@@ -656,10 +2210,23 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_setscheduler_batch() -> Result<(), Box<dyn Error>> {
+        setscheduler(0, Policy::Batch)?;
+        assert_eq!(getscheduler().unwrap(), "SCHED_BATCH");
+        Ok(())
+    }
+
+    #[test]
+    fn test_setscheduler_deadline_fail() {
+        // Needs setscheduler_deadline
+        assert!(setscheduler(0, Policy::Deadline).is_err());
+    }
+
     #[test]
     fn test_setscheduler_fifo() -> Result<(), Box<dyn Error>> {
         match setscheduler(99, Policy::Fifo) {
-            Ok(()) => return Err("Should fail".into()),
+            Ok(()) => Err("Should fail".into()),
             Err(_) => Ok(()),
         }
     }
@@ -667,18 +2234,147 @@ mod test {
     #[test]
     fn test_setscheduler_rr() -> Result<(), Box<dyn Error>> {
         match setscheduler(99, Policy::Rr) {
-            Ok(()) => return Err("Should fail".into()),
+            Ok(()) => Err("Should fail".into()),
             Err(_) => Ok(()),
         }
     }
 
+    #[test]
+    fn test_suffixed_path() {
+        let path = suffixed_path(Path::new("/tmp/results.json"), "timerfd");
+        assert_eq!(path, Path::new("/tmp/results-timerfd.json"));
+        let path = suffixed_path(Path::new("samples"), "nanosleep-spin");
+        assert_eq!(path, Path::new("samples-nanosleep-spin"));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(86_400)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(172_800)));
+    }
+
+    #[test]
+    fn test_parse_duration_fail() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+
+    #[test]
+    fn test_interval_ns() {
+        let config = MeasurementConfig::default()
+            .interval_us(5_000_000)
+            .distance_us(500);
+        assert_eq!(config.interval_ns(0), 5_000_000_000);
+        assert_eq!(config.interval_ns(11), 5_005_500_000);
+        assert!(run(&config.interval_us(0)).is_err());
+    }
+
+    #[test]
+    fn test_run_invalid_config() {
+        // Rejected instead of panicking in the measurement thread
+        let config = MeasurementConfig::default().histogram(15, 0);
+        assert!(run(&config).is_err());
+        assert!(Monitor::start(&config).is_err());
+        assert!(Monitor::start(&config.histogram(15, 1_000).interval_us(0)).is_err());
+    }
+
+    #[test]
+    fn test_thread_priority() {
+        let config = MeasurementConfig::default().priority(3);
+        let priorities: Vec<i32> = (0..4).map(|t| config.thread_priority(t)).collect();
+        assert_eq!(priorities, vec![3, 2, 1, 1]);
+        let config = config.descending_priority(false);
+        assert_eq!(config.thread_priority(3), 3);
+        let config = config.policy(Policy::Other);
+        assert_eq!(config.thread_priority(0), 0);
+    }
+
+    #[test]
+    fn test_sample_posix_timer() -> Result<(), Box<dyn Error>> {
+        block_alarm()?;
+        let param = test_param(50, TimerMode::Absolute);
+        let stats = Arc::new(Stats::new(1, 12, 1_000));
+        sample_posix_timer(Arc::clone(&stats), param)?;
+        assert_eq!(stats.snapshot()[0].cycles, 50);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_timerfd() -> Result<(), Box<dyn Error>> {
+        let param = test_param(50, TimerMode::Absolute);
+        let stats = Arc::new(Stats::new(1, 12, 1_000));
+        sample_timerfd(Arc::clone(&stats), param)?;
+        assert_eq!(stats.snapshot()[0].cycles, 50);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_stop() {
+        // An endless run returns after the first cycle when stop is set
+        let param = ThreadParam {
+            stop: Arc::new(AtomicBool::new(true)),
+            ..test_param(0, TimerMode::Absolute)
+        };
+        let stats = Arc::new(Stats::new(1, 12, 1_000));
+        sample_clock_nanosleep_with_gettime(Arc::clone(&stats), param).unwrap();
+        assert_eq!(stats.snapshot()[0].cycles, 0);
+    }
+
+    #[test]
+    fn test_clock_gettime_clocks() {
+        for clock in [
+            Clock::Monotonic,
+            Clock::Realtime,
+            Clock::Tai,
+            Clock::Boottime,
+        ] {
+            let begin = clock_gettime(clock.clockid());
+            sleep_clock_nanosleep(clock.clockid(), 1_000);
+            let end = clock_gettime(clock.clockid());
+            assert!(Timespec::diff_ns(begin, end) >= 1_000, "{}", clock.name());
+        }
+    }
+
     #[test]
     fn test_clock_gettime() {
-        let begin = clock_gettime();
-        let end = clock_gettime();
+        let begin = clock_gettime(libc::CLOCK_MONOTONIC);
+        let end = clock_gettime(libc::CLOCK_MONOTONIC);
         assert!(Timespec::diff_ns(begin, end) > 0);
     }
 
+    #[test]
+    fn test_add_ns() {
+        let begin = Timespec { sec: 1, nsec: 10 };
+        assert_eq!(begin.add_ns(20), Timespec { sec: 1, nsec: 30 });
+    }
+
+    #[test]
+    fn test_sub_ns() {
+        let t = Timespec { sec: 2, nsec: 100 };
+        assert_eq!(t.sub_ns(50), Timespec { sec: 2, nsec: 50 });
+        assert_eq!(
+            t.sub_ns(1_000_000_200),
+            Timespec {
+                sec: 0,
+                nsec: 999_999_900
+            }
+        );
+    }
+
+    #[test]
+    fn test_add_ns_overflow() {
+        let begin = Timespec {
+            sec: 1,
+            nsec: 999_999_990,
+        };
+        assert_eq!(begin.add_ns(1_000_000_020), Timespec { sec: 3, nsec: 10 });
+    }
+
     #[test]
     fn test_diff_larger() {
         let begin = Timespec { sec: 0, nsec: 10 };
@@ -713,25 +2409,119 @@ mod test {
         assert_eq!(Timespec::diff_ns(begin, end), 20);
     }
 
+    #[test]
+    fn test_thread_stats_record() {
+        let shared = SharedThreadStats::new(2, 500);
+        for (cycle, latency) in [100, 600, 1_000, 1_100, 2_000].iter().enumerate() {
+            shared.record(*latency, cycle as u64 + 1);
+        }
+        let stats = shared.snapshot();
+        assert_eq!(stats.hist, vec![1, 1]);
+        assert_eq!(stats.overflows, 3);
+        // Only as many outliers as buckets are kept
+        assert_eq!(stats.outliers, vec![3, 4]);
+        assert_eq!((stats.min, stats.average(), stats.max), (100, 960, 2_000));
+    }
+
+    #[test]
+    fn test_stats_snapshot_while_running() {
+        let stats = Arc::new(Stats::new(1, 10, 1_000));
+        let writer = {
+            let stats = Arc::clone(&stats);
+            thread::spawn(move || {
+                for cycle in 1..=100_000 {
+                    stats.threads[0].record(cycle % 12 * 1_000, cycle);
+                }
+            })
+        };
+        while !writer.is_finished() {
+            let stat = &stats.snapshot()[0];
+            let samples = stat.hist.iter().sum::<u32>() + stat.overflows;
+            assert!(samples as u64 >= stat.cycles);
+        }
+        writer.join().unwrap();
+        let stat = &stats.snapshot()[0];
+        assert_eq!(stat.cycles, 100_000);
+        assert_eq!(stat.outliers.len(), 10);
+    }
+
+    #[test]
+    fn test_thread_stats_record_wakeup() {
+        let shared = SharedThreadStats::new(2, 500);
+        for (latency_ns, late) in [(3_000, false), (25_000, true)] {
+            shared.record_wakeup(Wakeup { latency_ns, late });
+        }
+        let stats = shared.snapshot();
+        assert_eq!((stats.wakeup_min, stats.wakeup_average()), (3_000, 14_000));
+        assert_eq!((stats.wakeup_max, stats.late_wakeups), (25_000, 1));
+    }
+
+    #[test]
+    fn test_thread_stats_sample_rusage() {
+        let shared = SharedThreadStats::new(2, 1_000);
+        let rusage = |minor_faults, involuntary_switches| Rusage {
+            minor_faults,
+            involuntary_switches,
+            ..Default::default()
+        };
+        shared.start_rusage(rusage(10, 0));
+        shared.record(5_000, 1);
+        shared.sample_rusage(rusage(12, 0));
+        shared.record(500, 2);
+        shared.record(7_000, 3);
+        assert_eq!(
+            shared.snapshot().outlier_rusage,
+            vec![Some(rusage(2, 0)), None]
+        );
+        shared.sample_rusage(rusage(12, 1));
+        // A third overflow has no outlier entry left
+        shared.record(9_000, 4);
+        shared.sample_rusage(rusage(13, 1));
+        let stats = shared.snapshot();
+        assert_eq!(stats.outliers, vec![1, 3]);
+        assert_eq!(
+            stats.outlier_rusage,
+            vec![Some(rusage(2, 0)), Some(rusage(0, 1))]
+        );
+        // The third overflow is counted all the same
+        assert_eq!(stats.disturbed_overflows, 3);
+        shared.record(9_000, 5);
+        shared.sample_rusage(rusage(13, 1));
+        assert_eq!(shared.snapshot().disturbed_overflows, 3);
+    }
+
     // Sleep tests
 
     #[test]
     fn test_sample_sleep_with_duration() -> Result<(), Box<dyn Error>> {
-        sample_sleep_with_duration(1, 1)?;
+        let param = ThreadParam {
+            thread_num: 1,
+            interval: 100_000,
+            sleep_backend: SleepBackend::ThreadSleep,
+            ..test_param(50, TimerMode::Relative)
+        };
+        let stats = Arc::new(Stats::new(2, 10, 1_000));
+        sample_sleep_with_duration(Arc::clone(&stats), param)?;
+        assert_eq!(stats.snapshot()[1].cycles, 50);
         Ok(())
     }
 
     #[test]
     fn test_sample_clock_nanosleep_with_duration() {
-        let param = ThreadParam {
-            thread_num: 0,
-            interval: 1_000_000,
-            cycles: 1000,
-            sleep_fn: sleep_clock_nanosleep,
-            hist_size: 12,
-        };
-        let stats_data = Stats::new(12, 20);
-        let stats = Arc::new(Mutex::new(stats_data));
-        sample_clock_nanosleep_with_duration(stats, param);
+        let param = test_param(1000, TimerMode::Relative);
+        let stats = Arc::new(Stats::new(12, 20, 1_000));
+        sample_clock_nanosleep_with_duration(stats, param).unwrap();
+    }
+
+    #[test]
+    fn test_sample_clock_nanosleep_with_gettime_absolute() {
+        let param = test_param(100, TimerMode::Absolute);
+        let stats = Arc::new(Stats::new(1, 12, 1_000));
+        sample_clock_nanosleep_with_gettime(Arc::clone(&stats), param).unwrap();
+        let stat = &stats.snapshot()[0];
+        let samples: u32 = stat.hist.iter().sum::<u32>() + stat.overflows;
+        assert_eq!(samples, 100);
+        assert_eq!(stat.cycles, 100);
+        assert!(stat.min <= stat.max);
     }
 }
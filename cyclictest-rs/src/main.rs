@@ -1,7 +1,13 @@
 //use cyclictest_rs;
-use std::error::Error;
+use std::process::ExitCode;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    cyclictest_rs::cyclictest_main()?;
-    Ok(())
+fn main() -> ExitCode {
+    // Display, not Debug, so an RtError reads like its message
+    match cyclictest_rs::cyclictest_main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,141 @@
+//! Memory preparation before the measurement loops
+//!
+//! mlockall keeps mapped pages in memory, but the first touch of a stack or
+//! heap page still faults. Following the application base of the real-time
+//! Linux wiki, the heap is kept from shrinking or using mmap, a reserve is
+//! faulted in once, and each thread touches its stack before measuring.
+//!
+//! https://wiki.linuxfoundation.org/realtime/documentation/howto/applications/application_base
+
+use crate::RtError;
+
+/// Stack size of the measurement threads with prefaulting, the Rust default
+pub(crate) const DEFAULT_STACK_SIZE: usize = 2 << 20;
+/// Left untouched for the frames above the prefault and the measurement loop
+pub(crate) const STACK_RESERVE: usize = 64 << 10;
+pub(crate) const MIN_STACK_SIZE: usize = 2 * STACK_RESERVE;
+pub(crate) const PAGE_SIZE: usize = 4096;
+
+pub(crate) fn tune_malloc() -> Result<(), RtError> {
+    //! Keep freed memory in the heap and serve all allocations from it
+    // mallopt returns 1 on success and does not set errno
+    if unsafe { libc::mallopt(libc::M_TRIM_THRESHOLD, -1) } != 1 {
+        return Err(RtError::Invalid {
+            call: "mallopt",
+            reason: "M_TRIM_THRESHOLD rejected",
+        });
+    }
+    if unsafe { libc::mallopt(libc::M_MMAP_MAX, 0) } != 1 {
+        return Err(RtError::Invalid {
+            call: "mallopt",
+            reason: "M_MMAP_MAX rejected",
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn reserve_heap(bytes: usize) -> Result<(), RtError> {
+    //! Fault in bytes of heap and free them again
+    //!
+    //! After tune_malloc() the pages stay in the heap, later allocations up
+    //! to this size don't fault.
+    let mut reserve: Vec<u8> = vec![];
+    reserve
+        .try_reserve_exact(bytes)
+        .map_err(|_| RtError::Invalid {
+            call: "reserve_heap",
+            reason: "the heap reserve can't be allocated",
+        })?;
+    let pages = reserve.spare_capacity_mut();
+    for i in (0..pages.len()).step_by(PAGE_SIZE) {
+        pages[i].write(0);
+    }
+    std::hint::black_box(&reserve);
+    Ok(())
+}
+
+#[inline(never)]
+pub(crate) fn prefault_stack(bytes: usize) {
+    //! Touch bytes of stack below the caller, one page per call
+    let mut page = [0u8; PAGE_SIZE];
+    std::hint::black_box(&mut page);
+    if bytes > PAGE_SIZE {
+        prefault_stack(bytes - PAGE_SIZE);
+    }
+    // The page is used after the call, so the recursion can't be turned
+    // into a loop that reuses one frame
+    std::hint::black_box(&page);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Rusage;
+    use std::thread;
+
+    /// Stack used by use_stack, within the prefaulted part of the stacks below
+    const USED: usize = 128 << 10;
+
+    #[inline(never)]
+    fn use_stack() {
+        //! One frame over USED bytes that writes each of its pages
+        let mut frame = [0u8; USED];
+        for i in (0..USED).step_by(PAGE_SIZE) {
+            frame[i] = 1;
+        }
+        std::hint::black_box(&frame);
+    }
+
+    fn use_stack_faults(size: usize, prefault: bool) -> u64 {
+        //! The page faults of use_stack in a new thread
+        let handle = thread::Builder::new()
+            .stack_size(size)
+            .spawn(move || {
+                if prefault {
+                    prefault_stack(size - STACK_RESERVE);
+                }
+                let before = Rusage::thread().minor_faults;
+                use_stack();
+                Rusage::thread().minor_faults - before
+            })
+            .unwrap();
+        handle.join().unwrap()
+    }
+
+    #[test]
+    fn test_prefault_stack() {
+        if std::env::var_os("PREFAULT_STACK_CHILD").is_none() {
+            // After mlockall with MCL_FUTURE in test_mlockall new stacks are
+            // populated up front, so compare in a process of this test alone
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["memory::test::test_prefault_stack", "--exact"])
+                .env("PREFAULT_STACK_CHILD", "1")
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+            return;
+        }
+        // The control gets the larger stack, so it can't reuse the stack of
+        // the prefaulted thread that glibc caches after the join
+        let prefaulted = use_stack_faults(256 << 10, true);
+        let control = use_stack_faults(512 << 10, false);
+        assert!(
+            control >= (USED / PAGE_SIZE / 2) as u64,
+            "{} faults without prefaulting",
+            control
+        );
+        assert!(prefaulted < 4, "{} faults after prefaulting", prefaulted);
+    }
+
+    #[test]
+    fn test_reserve_heap() -> Result<(), RtError> {
+        reserve_heap(1 << 20)?;
+        reserve_heap(0)?;
+        assert!(reserve_heap(usize::MAX).is_err());
+        Ok(())
+    }
+}
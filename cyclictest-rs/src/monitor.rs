@@ -0,0 +1,167 @@
+//! Latency monitor that runs next to a real-time application
+//!
+//! One measurement thread with the clock_nanosleep and clock_gettime loop
+//! keeps measuring the wakeup latency of the host until it is stopped. Unlike
+//! run() it leaves the process alone: no mlockall, no signal handlers and no
+//! /dev/cpu_dma_latency, these are up to the application.
+
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::{
+    sample_clock_nanosleep_with_gettime, setup_thread, BreakInfo, MeasurementConfig, RtError,
+    Stats, ThreadParam, ThreadStats,
+};
+
+/// Called from the monitor thread for every cycle above the threshold
+pub(crate) type ThresholdCallback = Arc<dyn Fn(BreakInfo) + Send + Sync>;
+
+/// Latency threshold of a Monitor
+#[derive(Clone)]
+pub(crate) struct Threshold {
+    pub latency_ns: u64,
+    pub callback: ThresholdCallback,
+}
+
+/// Background latency measurement, the thread is stopped on drop
+pub struct Monitor {
+    stats: Arc<Stats>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<Result<(), RtError>>>,
+}
+
+impl Monitor {
+    pub fn start(config: &MeasurementConfig) -> Result<Monitor, Box<dyn Error>> {
+        //! Start the monitor thread without a threshold
+        Monitor::spawn(config, None)
+    }
+
+    pub fn start_with_threshold<F>(
+        config: &MeasurementConfig,
+        threshold_us: u64,
+        callback: F,
+    ) -> Result<Monitor, Box<dyn Error>>
+    where
+        F: Fn(BreakInfo) + Send + Sync + 'static,
+    {
+        //! Start the monitor thread and call back when a latency exceeds the
+        //! threshold
+        //!
+        //! The callback runs in the monitor thread at its priority, so it
+        //! should only hand the event over, e.g. into a channel or an atomic.
+        let threshold = Threshold {
+            latency_ns: threshold_us * 1_000,
+            callback: Arc::new(callback),
+        };
+        Monitor::spawn(config, Some(threshold))
+    }
+
+    fn spawn(
+        config: &MeasurementConfig,
+        threshold: Option<Threshold>,
+    ) -> Result<Monitor, Box<dyn Error>> {
+        //! The thread uses the interval, priority, policy, clock, sleep
+        //! backend, timer mode, histogram and the first CPU of the config.
+        //! It runs until the monitor is stopped, loops are ignored.
+        config.validate()?;
+        let stats = Arc::new(Stats::new(1, config.hist_size, config.hist_bucket_ns));
+        let stop = Arc::new(AtomicBool::new(false));
+        let policy = config.policy;
+        let param = ThreadParam {
+            thread_num: 0,
+            interval: config.interval_ns(0),
+            cycles: 0,
+            priority: config.thread_priority(0),
+            sleep_backend: config.sleep_backend,
+            spin_margin: config.spin_margin_us as u64 * 1_000,
+            clock: config.clock,
+            timer_mode: config.timer_mode,
+            stop: Arc::clone(&stop),
+            breaktrace: None,
+            threshold,
+            prefault_stack: 0,
+            rusage_interval: 0,
+        };
+        let cpu = config.cpus.as_ref().and_then(|cpus| cpus.first().copied());
+        let deadline = config.deadline;
+        let thread_stats = Arc::clone(&stats);
+        let handle = thread::Builder::new()
+            .name("latency-monitor".into())
+            .spawn(move || -> Result<(), RtError> {
+                setup_thread(&param, cpu, policy, deadline)?;
+                sample_clock_nanosleep_with_gettime(thread_stats, param)
+            })?;
+        Ok(Monitor {
+            stats,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn snapshot(&self) -> ThreadStats {
+        //! Current min/avg/max and histogram, never blocks the monitor thread
+        self.stats.threads[0].snapshot()
+    }
+
+    pub fn is_running(&self) -> bool {
+        //! False when the thread could not be set up or has failed
+        self.handle.as_ref().is_some_and(|h| !h.is_finished())
+    }
+
+    pub fn stop(mut self) -> Result<ThreadStats, Box<dyn Error>> {
+        //! Stop the thread after its current cycle and return the results
+        self.join()?;
+        Ok(self.snapshot())
+    }
+
+    fn join(&mut self) -> Result<(), Box<dyn Error>> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.handle.take().map(|h| h.join()) {
+            Some(Ok(Err(e))) => Err(format!("Monitor: {}", e).into()),
+            Some(Err(_)) => Err("Monitor thread panicked".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MeasurementType, Policy};
+    use std::sync::atomic::AtomicU64;
+    use std::time::Duration;
+
+    #[test]
+    fn test_monitor() -> Result<(), Box<dyn Error>> {
+        let config = MeasurementConfig::new(MeasurementType::ClockNanosleepGettime)
+            .interval_us(200)
+            .policy(Policy::Other)
+            .priority(0);
+        let hits = Arc::new(AtomicU64::new(0));
+        let monitor = {
+            let hits = Arc::clone(&hits);
+            Monitor::start_with_threshold(&config, 0, move |_info| {
+                hits.fetch_add(1, Ordering::Relaxed);
+            })?
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(monitor.is_running());
+        assert!(monitor.snapshot().cycles > 0);
+
+        let stats = monitor.stop()?;
+        let samples = stats.hist.iter().sum::<u32>() + stats.overflows;
+        assert_eq!(samples as u64, stats.cycles);
+        // Practically every cycle is above a threshold of 0
+        let hits = hits.load(Ordering::Relaxed);
+        assert!(hits > 0 && hits <= stats.cycles);
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+//! Per-thread perf_event_open counters around the measurement loop
+//!
+//! The software events count on any machine, the hardware events only where
+//! the PMU is accessible, e.g. often not in virtual machines. A counter that
+//! cannot be opened is reported as not available instead of failing the run.
+//! Counting kernel events needs a perf_event_paranoid below 2 or
+//! CAP_PERFMON.
+
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+const PERF_COUNT_SW_CPU_MIGRATIONS: u64 = 4;
+const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1;
+const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 2;
+/// attr.flags bit
+const DISABLED: u64 = 1;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 0x2401;
+
+/// The events in the order of PerfCounters::events
+const EVENTS: [(u32, u64); 5] = [
+    (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CONTEXT_SWITCHES),
+    (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CPU_MIGRATIONS),
+    (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_PAGE_FAULTS),
+    (PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS),
+    (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CACHE_MISSES),
+];
+
+/// First version of struct perf_event_attr, see include/uapi/linux/perf_event.h
+#[repr(C)]
+#[derive(Debug, Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// Counter values of one thread, None where the event is not available
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PerfCounters {
+    pub context_switches: Option<u64>,
+    pub cpu_migrations: Option<u64>,
+    pub page_faults: Option<u64>,
+    pub instructions: Option<u64>,
+    pub cache_misses: Option<u64>,
+}
+
+impl PerfCounters {
+    fn from_events(values: [Option<u64>; 5]) -> PerfCounters {
+        PerfCounters {
+            context_switches: values[0],
+            cpu_migrations: values[1],
+            page_faults: values[2],
+            instructions: values[3],
+            cache_misses: values[4],
+        }
+    }
+
+    pub(crate) fn events(&self) -> [(&'static str, Option<u64>); 5] {
+        //! The counters with their names in the result file
+        [
+            ("context_switches", self.context_switches),
+            ("cpu_migrations", self.cpu_migrations),
+            ("page_faults", self.page_faults),
+            ("instructions", self.instructions),
+            ("cache_misses", self.cache_misses),
+        ]
+    }
+}
+
+/// Open counters of the calling thread
+pub(crate) struct Counters {
+    fds: [Option<OwnedFd>; 5],
+}
+
+fn perf_event_open(type_: u32, config: u64) -> Option<OwnedFd> {
+    let attr = PerfEventAttr {
+        type_,
+        size: std::mem::size_of::<PerfEventAttr>() as u32,
+        config,
+        read_format: PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING,
+        flags: DISABLED,
+        ..Default::default()
+    };
+    // pid 0 and cpu -1 count the calling thread on any CPU
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr,
+            0,
+            -1,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    match fd {
+        fd if fd >= 0 => Some(unsafe { OwnedFd::from_raw_fd(fd as i32) }),
+        _ => None,
+    }
+}
+
+fn read_counter(fd: &OwnedFd) -> Option<u64> {
+    //! The value, scaled up if the PMU had to multiplex the counter
+    let mut values = [0u64; 3];
+    let len = std::mem::size_of_val(&values);
+    let ret = unsafe {
+        libc::read(
+            fd.as_raw_fd(),
+            values.as_mut_ptr() as *mut libc::c_void,
+            len,
+        )
+    };
+    let [value, enabled, running] = values;
+    match ret as usize == len {
+        true if running == 0 => Some(0),
+        true => Some((value as u128 * enabled as u128 / running as u128) as u64),
+        false => None,
+    }
+}
+
+impl Counters {
+    pub fn open() -> Counters {
+        //! Open the counters for the calling thread, they start disabled
+        Counters {
+            fds: EVENTS.map(|(type_, config)| perf_event_open(type_, config)),
+        }
+    }
+
+    pub fn enable(&self) {
+        for fd in self.fds.iter().flatten() {
+            unsafe { libc::ioctl(fd.as_raw_fd(), PERF_EVENT_IOC_ENABLE, 0) };
+        }
+    }
+
+    pub fn read(&self) -> PerfCounters {
+        //! Stop the counters and return their values
+        for fd in self.fds.iter().flatten() {
+            unsafe { libc::ioctl(fd.as_raw_fd(), PERF_EVENT_IOC_DISABLE, 0) };
+        }
+        let values = self
+            .fds
+            .each_ref()
+            .map(|fd| fd.as_ref().and_then(read_counter));
+        PerfCounters::from_events(values)
+    }
+}
+
+/// PerfCounters that the measurement thread writes and others read
+pub(crate) struct AtomicPerfCounters {
+    /// u64::MAX for an event that is not available
+    values: [AtomicU64; 5],
+}
+
+impl Default for AtomicPerfCounters {
+    fn default() -> AtomicPerfCounters {
+        AtomicPerfCounters {
+            values: [0; 5].map(|_| AtomicU64::new(u64::MAX)),
+        }
+    }
+}
+
+impl AtomicPerfCounters {
+    pub fn store(&self, counters: PerfCounters) {
+        for (value, (_, count)) in self.values.iter().zip(counters.events()) {
+            value.store(count.unwrap_or(u64::MAX), Ordering::Relaxed);
+        }
+    }
+
+    pub fn load(&self) -> PerfCounters {
+        PerfCounters::from_events(self.values.each_ref().map(|value| {
+            match value.load(Ordering::Relaxed) {
+                u64::MAX => None,
+                count => Some(count),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_attr_size() {
+        // PERF_ATTR_SIZE_VER0
+        assert_eq!(std::mem::size_of::<PerfEventAttr>(), 64);
+    }
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::open();
+        counters.enable();
+        for _ in 0..10 {
+            std::thread::sleep(std::time::Duration::from_micros(100));
+        }
+        let values = counters.read();
+        // Not available with a perf_event_paranoid of 2 or more
+        if let Some(switches) = values.context_switches {
+            assert!(switches >= 10);
+        }
+
+        let atomic = AtomicPerfCounters::default();
+        assert_eq!(atomic.load(), PerfCounters::default());
+        atomic.store(values);
+        assert_eq!(atomic.load(), values);
+    }
+}
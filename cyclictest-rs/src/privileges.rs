@@ -0,0 +1,128 @@
+//! Detection of the privileges a real-time measurement needs
+//!
+//! Without them the setup calls fail one after another in the threads, so
+//! run() checks up front and either refuses with the complete list or, when
+//! the config allows it, falls back to a degraded run under SCHED_OTHER.
+//! Write access to /dev/cpu_dma_latency is not one of them, without it the
+//! run keeps the real-time policy and only power management stays on.
+
+use std::ffi::CStr;
+use std::fs;
+use std::mem;
+
+use crate::{MeasurementConfig, Policy};
+
+const CAP_IPC_LOCK: u32 = 14;
+const CAP_SYS_NICE: u32 = 23;
+
+/// Capabilities and resource limits of the process
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Privileges {
+    pub cap_sys_nice: bool,
+    pub cap_ipc_lock: bool,
+    /// Soft limit, the highest priority allowed without CAP_SYS_NICE
+    pub rlimit_rtprio: u64,
+    /// Soft limit in bytes, u64::MAX for unlimited
+    pub rlimit_memlock: u64,
+    /// /dev/cpu_dma_latency can be opened for writing, not needed for a
+    /// real-time run
+    pub dma_latency: bool,
+}
+
+fn parse_cap_eff(status: &str) -> Option<u64> {
+    //! The effective capability set from /proc/self/status
+    let hex = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(hex.trim(), 16).ok()
+}
+
+fn getrlimit(resource: libc::__rlimit_resource_t) -> u64 {
+    let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+    match unsafe { libc::getrlimit(resource, &mut limit) } {
+        0 if limit.rlim_cur == libc::RLIM_INFINITY => u64::MAX,
+        0 => limit.rlim_cur,
+        _ => 0,
+    }
+}
+
+impl Privileges {
+    pub fn detect() -> Privileges {
+        let caps = fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| parse_cap_eff(&status))
+            .unwrap_or(0);
+        let path: &CStr = c"/dev/cpu_dma_latency";
+        Privileges {
+            cap_sys_nice: caps & (1 << CAP_SYS_NICE) != 0,
+            cap_ipc_lock: caps & (1 << CAP_IPC_LOCK) != 0,
+            rlimit_rtprio: getrlimit(libc::RLIMIT_RTPRIO),
+            rlimit_memlock: getrlimit(libc::RLIMIT_MEMLOCK),
+            dma_latency: unsafe { libc::access(path.as_ptr(), libc::W_OK) } == 0,
+        }
+    }
+
+    pub fn missing(&self, config: &MeasurementConfig) -> Vec<&'static str> {
+        //! What prevents a real-time run of the config, empty if nothing
+        let mut missing = vec![];
+        let priority_ok = match config.policy {
+            Policy::Fifo | Policy::Rr => {
+                self.cap_sys_nice || self.rlimit_rtprio >= config.priority as u64
+            }
+            // RLIMIT_RTPRIO does not cover SCHED_DEADLINE
+            Policy::Deadline => self.cap_sys_nice,
+            Policy::Other | Policy::Batch | Policy::Idle => true,
+        };
+        if !priority_ok {
+            missing.push("CAP_SYS_NICE or RLIMIT_RTPRIO for the priority");
+        }
+        // MCL_FUTURE has to cover the thread stacks and everything after,
+        // only an unlimited RLIMIT_MEMLOCK is sure to be enough
+        if !(self.cap_ipc_lock || self.rlimit_memlock == u64::MAX) {
+            missing.push("CAP_IPC_LOCK or unlimited RLIMIT_MEMLOCK");
+        }
+        missing
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_cap_eff() {
+        let status = "Name:\tcat\nCapPrm:\t0000000000000000\nCapEff:\t000001ffffffffff\n";
+        assert_eq!(parse_cap_eff(status), Some(0x1ff_ffff_ffff));
+        assert_eq!(parse_cap_eff("Name:\tcat\n"), None);
+    }
+
+    #[test]
+    fn test_missing() {
+        let user = Privileges {
+            cap_sys_nice: false,
+            cap_ipc_lock: false,
+            rlimit_rtprio: 0,
+            rlimit_memlock: 8 << 20,
+            dma_latency: false,
+        };
+        let config = MeasurementConfig::default();
+        assert_eq!(user.missing(&config).len(), 2);
+        let config = config.policy(Policy::Other).priority(0);
+        assert_eq!(user.missing(&config).len(), 1);
+
+        let rtgroup = Privileges {
+            rlimit_rtprio: 95,
+            rlimit_memlock: u64::MAX,
+            ..user
+        };
+        let config = config.policy(Policy::Fifo).priority(95);
+        assert!(rtgroup.missing(&config).is_empty());
+        let config = config.priority(99);
+        assert_eq!(
+            rtgroup.missing(&config),
+            vec!["CAP_SYS_NICE or RLIMIT_RTPRIO for the priority"]
+        );
+        let config = config.policy(Policy::Deadline);
+        assert_eq!(rtgroup.missing(&config).len(), 1);
+    }
+}
@@ -0,0 +1,330 @@
+//! Raw samples of every cycle for post-mortem analysis of rare spikes
+//!
+//! Each measurement thread writes into its own ring buffer that is allocated
+//! and faulted in before the loop starts, the measurement path never
+//! allocates. When a run has more cycles than the buffer holds, the oldest
+//! samples are overwritten. The buffers are read after the threads are
+//! joined and can be written with the results of the threads to a compact
+//! binary file, e.g. for the analyze subcommand:
+//!
+//! ```text
+//! magic       8 bytes "CTRSAMP\0"
+//! version     u32, 3
+//! clockid     i32, the clock of the wakeup timestamps
+//! checks      u64, the preflight checks of the host
+//! per check:
+//!     status      u8, 0 pass, 1 warn, 2 fail
+//!     name, value, explanation as u32 length and UTF-8 bytes each
+//! threads     u64
+//! per thread:
+//!     cpu         i64, -1 if not pinned
+//!     interval_ns u64
+//!     cycles, overruns, min, max, sum of the whole run, u64 each
+//!     count       u64
+//!     count samples of cycle u64, wakeup_ns u64, latency_ns u64
+//! ```
+//!
+//! All numbers are little-endian.
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::memory::PAGE_SIZE;
+use crate::{Check, CheckStatus};
+
+const MAGIC: [u8; 8] = *b"CTRSAMP\0";
+const VERSION: u32 = 3;
+/// Words of a sample in the ring
+const WORDS: usize = 3;
+
+/// One cycle of a measurement thread
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sample {
+    pub cycle: u64,
+    /// Time of the wakeup on the clock of the measurement in ns
+    pub wakeup_ns: u64,
+    pub latency_ns: u64,
+}
+
+/// Results and samples of one thread, see ThreadStats for the results
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThreadRecord {
+    /// The CPU the thread was pinned to
+    pub cpu: Option<usize>,
+    pub interval_ns: u64,
+    pub cycles: u64,
+    pub overruns: u64,
+    pub min: u64,
+    pub max: u64,
+    pub sum: u64,
+    /// The last samples of the run, oldest first
+    pub samples: Vec<Sample>,
+}
+
+/// Contents of a file of --record
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recording {
+    /// Clock of the wakeup timestamps
+    pub clockid: libc::clockid_t,
+    /// Preflight checks of the host at the time of the run
+    pub checks: Vec<Check>,
+    pub threads: Vec<ThreadRecord>,
+}
+
+/// Ring buffer of samples that the measurement thread writes
+pub(crate) struct SampleRing {
+    words: Box<[AtomicU64]>,
+    /// Samples pushed so far, including the overwritten ones
+    pushed: AtomicU64,
+}
+
+impl SampleRing {
+    pub fn new(capacity: usize) -> SampleRing {
+        //! Allocate and fault in room for capacity samples, 0 records nothing
+        let words: Box<[AtomicU64]> = (0..capacity * WORDS).map(|_| AtomicU64::new(0)).collect();
+        // The zeroed allocation may not be backed by pages yet
+        for word in words.iter().step_by(PAGE_SIZE / 8) {
+            word.store(0, Ordering::Relaxed);
+        }
+        SampleRing {
+            words,
+            pushed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.words.len() / WORDS
+    }
+
+    pub fn push(&self, sample: Sample) {
+        //! Only called by the measurement thread
+        let capacity = self.capacity() as u64;
+        if capacity == 0 {
+            return;
+        }
+        let pushed = self.pushed.load(Ordering::Relaxed);
+        let i = (pushed % capacity) as usize * WORDS;
+        self.words[i].store(sample.cycle, Ordering::Relaxed);
+        self.words[i + 1].store(sample.wakeup_ns, Ordering::Relaxed);
+        self.words[i + 2].store(sample.latency_ns, Ordering::Relaxed);
+        self.pushed.store(pushed + 1, Ordering::Release);
+    }
+
+    pub fn samples(&self) -> Vec<Sample> {
+        //! The samples in the buffer, oldest first
+        //!
+        //! Only complete after the measurement thread is finished.
+        let capacity = self.capacity() as u64;
+        let pushed = self.pushed.load(Ordering::Acquire);
+        let first = pushed.saturating_sub(capacity);
+        (first..pushed)
+            .map(|n| {
+                let i = (n % capacity) as usize * WORDS;
+                Sample {
+                    cycle: self.words[i].load(Ordering::Relaxed),
+                    wakeup_ns: self.words[i + 1].load(Ordering::Relaxed),
+                    latency_ns: self.words[i + 2].load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+}
+
+pub fn write_recording(w: &mut impl Write, recording: &Recording) -> io::Result<()> {
+    //! Write a recording in the binary format, see above
+    w.write_all(&MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&recording.clockid.to_le_bytes())?;
+    w.write_all(&(recording.checks.len() as u64).to_le_bytes())?;
+    for check in &recording.checks {
+        let status: u8 = match check.status {
+            CheckStatus::Pass => 0,
+            CheckStatus::Warn => 1,
+            CheckStatus::Fail => 2,
+        };
+        w.write_all(&[status])?;
+        for text in [&check.name, &check.value, &check.explanation] {
+            w.write_all(&(text.len() as u32).to_le_bytes())?;
+            w.write_all(text.as_bytes())?;
+        }
+    }
+    w.write_all(&(recording.threads.len() as u64).to_le_bytes())?;
+    for thread in &recording.threads {
+        let cpu = thread.cpu.map_or(-1, |cpu| cpu as i64);
+        w.write_all(&cpu.to_le_bytes())?;
+        for value in [
+            thread.interval_ns,
+            thread.cycles,
+            thread.overruns,
+            thread.min,
+            thread.max,
+            thread.sum,
+            thread.samples.len() as u64,
+        ] {
+            w.write_all(&value.to_le_bytes())?;
+        }
+        for sample in &thread.samples {
+            w.write_all(&sample.cycle.to_le_bytes())?;
+            w.write_all(&sample.wakeup_ns.to_le_bytes())?;
+            w.write_all(&sample.latency_ns.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<String> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    let len = u32::from_le_bytes(buf) as u64;
+    // Don't trust the length for the allocation either
+    let mut bytes = vec![];
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn read_recording(r: &mut impl Read) -> io::Result<Recording> {
+    //! Read a file of write_recording
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid("Not a sample file"));
+    }
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    if u32::from_le_bytes(buf) != VERSION {
+        return Err(invalid("Unsupported sample file version"));
+    }
+    r.read_exact(&mut buf)?;
+    let clockid = libc::clockid_t::from_le_bytes(buf);
+    let mut checks = vec![];
+    for _ in 0..read_u64(r)? {
+        let mut status = [0u8; 1];
+        r.read_exact(&mut status)?;
+        let status = match status[0] {
+            0 => CheckStatus::Pass,
+            1 => CheckStatus::Warn,
+            2 => CheckStatus::Fail,
+            _ => return Err(invalid("Invalid check status")),
+        };
+        checks.push(Check {
+            name: read_string(r)?,
+            status,
+            value: read_string(r)?,
+            explanation: read_string(r)?,
+        });
+    }
+    let num_threads = read_u64(r)?;
+    let mut threads = vec![];
+    for _ in 0..num_threads {
+        let cpu = read_u64(r)? as i64;
+        let mut thread = ThreadRecord {
+            cpu: (cpu >= 0).then_some(cpu as usize),
+            interval_ns: read_u64(r)?,
+            cycles: read_u64(r)?,
+            overruns: read_u64(r)?,
+            min: read_u64(r)?,
+            max: read_u64(r)?,
+            sum: read_u64(r)?,
+            samples: vec![],
+        };
+        // Don't trust the count for the allocation, the file may be truncated
+        for _ in 0..read_u64(r)? {
+            thread.samples.push(Sample {
+                cycle: read_u64(r)?,
+                wakeup_ns: read_u64(r)?,
+                latency_ns: read_u64(r)?,
+            });
+        }
+        threads.push(thread);
+    }
+    Ok(Recording {
+        clockid,
+        checks,
+        threads,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sample_ring() {
+        let ring = SampleRing::new(3);
+        let sample = |cycle| Sample {
+            cycle,
+            wakeup_ns: cycle * 1_000,
+            latency_ns: cycle + 10,
+        };
+        ring.push(sample(1));
+        ring.push(sample(2));
+        assert_eq!(ring.samples(), vec![sample(1), sample(2)]);
+        for cycle in 3..=5 {
+            ring.push(sample(cycle));
+        }
+        // The oldest are overwritten
+        assert_eq!(ring.samples(), vec![sample(3), sample(4), sample(5)]);
+
+        let empty = SampleRing::new(0);
+        empty.push(sample(1));
+        assert!(empty.samples().is_empty());
+    }
+
+    #[test]
+    fn test_write_read_recording() {
+        let recording = Recording {
+            clockid: libc::CLOCK_MONOTONIC,
+            checks: vec![Check {
+                name: "smt".to_string(),
+                status: CheckStatus::Warn,
+                value: "on".to_string(),
+                explanation: "µs".to_string(),
+            }],
+            threads: vec![
+                ThreadRecord {
+                    cpu: Some(3),
+                    interval_ns: 1_000_000,
+                    cycles: 2,
+                    overruns: 1,
+                    min: 3_500,
+                    max: u64::MAX,
+                    sum: 3_499,
+                    samples: vec![
+                        Sample {
+                            cycle: 1,
+                            wakeup_ns: 1_000_000,
+                            latency_ns: 3_500,
+                        },
+                        Sample {
+                            cycle: 2,
+                            wakeup_ns: 2_000_000,
+                            latency_ns: u64::MAX,
+                        },
+                    ],
+                },
+                ThreadRecord {
+                    cpu: None,
+                    ..Default::default()
+                },
+            ],
+        };
+        let mut file = vec![];
+        write_recording(&mut file, &recording).unwrap();
+        assert_eq!(file.len(), 32 + (1 + 3 * 4 + 3 + 2 + 3) + 2 * 64 + 2 * 24);
+        assert_eq!(read_recording(&mut file.as_slice()).unwrap(), recording);
+
+        assert!(read_recording(&mut &file[..100]).is_err());
+        assert!(read_recording(&mut &b"CTRSAMP\0"[..]).is_err());
+        assert!(read_recording(&mut &[0u8; 24][..]).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+//! Page faults and context switches of the measurement threads
+//!
+//! getrusage(RUSAGE_THREAD) is sampled every few cycles. The delta of a
+//! sample window is attributed to the spikes, i.e. the histogram overflows,
+//! recorded in it. With a sample every cycle a spike gets exactly the faults
+//! and switches of its own cycle.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of getrusage, totals or the delta of a sample window
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rusage {
+    pub minor_faults: u64,
+    pub major_faults: u64,
+    /// Blocked, e.g. sleeping until the next cycle
+    pub voluntary_switches: u64,
+    /// Preempted
+    pub involuntary_switches: u64,
+}
+
+impl Rusage {
+    pub(crate) fn thread() -> Rusage {
+        //! The counters of the calling thread so far
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) };
+        Rusage {
+            minor_faults: usage.ru_minflt as u64,
+            major_faults: usage.ru_majflt as u64,
+            voluntary_switches: usage.ru_nvcsw as u64,
+            involuntary_switches: usage.ru_nivcsw as u64,
+        }
+    }
+
+    pub(crate) fn disturbed(&self) -> bool {
+        //! Faults or preemptions, voluntary switches are expected from the
+        //! sleep of each cycle
+        self.minor_faults + self.major_faults + self.involuntary_switches > 0
+    }
+
+    pub(crate) fn since(&self, start: &Rusage) -> Rusage {
+        Rusage {
+            minor_faults: self.minor_faults.saturating_sub(start.minor_faults),
+            major_faults: self.major_faults.saturating_sub(start.major_faults),
+            voluntary_switches: self
+                .voluntary_switches
+                .saturating_sub(start.voluntary_switches),
+            involuntary_switches: self
+                .involuntary_switches
+                .saturating_sub(start.involuntary_switches),
+        }
+    }
+}
+
+impl fmt::Display for Rusage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        //! The non-zero counters, e.g. "2 minor faults, 1 involuntary switch"
+        let counters = [
+            (self.minor_faults, "minor fault"),
+            (self.major_faults, "major fault"),
+            (self.voluntary_switches, "voluntary switch"),
+            (self.involuntary_switches, "involuntary switch"),
+        ];
+        let mut comma = false;
+        for (count, name) in counters.iter().filter(|(count, _)| *count > 0) {
+            let plural = match (*count, name.ends_with('h')) {
+                (1, _) => "",
+                (_, true) => "es",
+                (_, false) => "s",
+            };
+            write!(
+                f,
+                "{}{} {}{}",
+                if comma { ", " } else { "" },
+                count,
+                name,
+                plural
+            )?;
+            comma = true;
+        }
+        match comma {
+            true => Ok(()),
+            false => write!(f, "none"),
+        }
+    }
+}
+
+/// Rusage that the measurement thread writes and others read
+#[derive(Default)]
+pub(crate) struct AtomicRusage {
+    minor_faults: AtomicU64,
+    major_faults: AtomicU64,
+    voluntary_switches: AtomicU64,
+    involuntary_switches: AtomicU64,
+}
+
+impl AtomicRusage {
+    pub fn store(&self, rusage: Rusage) {
+        self.minor_faults
+            .store(rusage.minor_faults, Ordering::Relaxed);
+        self.major_faults
+            .store(rusage.major_faults, Ordering::Relaxed);
+        self.voluntary_switches
+            .store(rusage.voluntary_switches, Ordering::Relaxed);
+        self.involuntary_switches
+            .store(rusage.involuntary_switches, Ordering::Relaxed);
+    }
+
+    pub fn load(&self) -> Rusage {
+        Rusage {
+            minor_faults: self.minor_faults.load(Ordering::Relaxed),
+            major_faults: self.major_faults.load(Ordering::Relaxed),
+            voluntary_switches: self.voluntary_switches.load(Ordering::Relaxed),
+            involuntary_switches: self.involuntary_switches.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rusage() {
+        let start = Rusage::thread();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let delta = Rusage::thread().since(&start);
+        assert!(delta.voluntary_switches >= 1);
+
+        let rusage = Rusage {
+            minor_faults: 2,
+            involuntary_switches: 1,
+            ..Default::default()
+        };
+        assert_eq!(rusage.to_string(), "2 minor faults, 1 involuntary switch");
+        assert_eq!(Rusage::default().to_string(), "none");
+        let atomic = AtomicRusage::default();
+        atomic.store(rusage);
+        assert_eq!(atomic.load(), rusage);
+    }
+}
@@ -0,0 +1,302 @@
+//! Sleep backends for the measurement threads
+//!
+//! The same measurement loop can sleep with different wakeup primitives to
+//! see which of them (and which std facilities) are usable in rt contexts.
+//! Only clock_nanosleep honours the selected clock, the other primitives use
+//! CLOCK_MONOTONIC internally.
+//!
+//! The spin backend sleeps until shortly before the deadline and busy-waits
+//! on clock_gettime for the rest, like sub-10 µs control loops do. It reports
+//! the wakeup latency of the sleep part separately from the deadline accuracy.
+
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::AtomicU32;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use errno::errno;
+
+use crate::{clock_gettime, sleep_clock_nanosleep, sleep_clock_nanosleep_abs, RtError, Timespec};
+
+/// Wakeup primitive used to sleep for an interval
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum SleepBackend {
+    /// clock_nanosleep, with TIMER_ABSTIME for absolute deadlines
+    ClockNanosleep,
+    /// std::thread::sleep
+    ThreadSleep,
+    /// std::thread::park_timeout
+    ParkTimeout,
+    /// std::sync::Condvar::wait_timeout
+    Condvar,
+    /// ppoll without file descriptors
+    Poll,
+    /// epoll_pwait2 on an empty epoll set, needs Linux 5.11
+    Epoll,
+    /// FUTEX_WAIT on a private futex word that never changes
+    Futex,
+    /// pselect without file descriptors
+    Select,
+    /// clock_nanosleep until deadline - spin margin, then spin on clock_gettime
+    Spin,
+}
+
+impl SleepBackend {
+    pub fn name(self) -> &'static str {
+        match self {
+            SleepBackend::ClockNanosleep => "clock_nanosleep",
+            SleepBackend::ThreadSleep => "std::thread::sleep",
+            SleepBackend::ParkTimeout => "std::thread::park_timeout",
+            SleepBackend::Condvar => "Condvar::wait_timeout",
+            SleepBackend::Poll => "ppoll",
+            SleepBackend::Epoll => "epoll_pwait2",
+            SleepBackend::Futex => "FUTEX_WAIT",
+            SleepBackend::Select => "pselect",
+            SleepBackend::Spin => "clock_nanosleep+spin",
+        }
+    }
+}
+
+/// State that some of the backends need, created per thread
+enum State {
+    None,
+    Condvar(Mutex<()>, Condvar),
+    Epoll(OwnedFd),
+    Futex(AtomicU32),
+}
+
+pub(crate) struct Sleeper {
+    backend: SleepBackend,
+    clockid: libc::clockid_t,
+    /// Only used by SleepBackend::Spin
+    spin_margin_ns: u64,
+    state: State,
+}
+
+/// The sleep part of a SleepBackend::Spin wakeup
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Wakeup {
+    /// Wakeup time - (deadline - spin margin)
+    pub latency_ns: u64,
+    /// The sleep returned after the deadline, the margin was too small
+    pub late: bool,
+}
+
+pub(crate) fn to_timespec(sleep_ns: u64) -> libc::timespec {
+    libc::timespec {
+        tv_sec: (sleep_ns / 1_000_000_000) as i64,
+        tv_nsec: (sleep_ns % 1_000_000_000) as i64,
+    }
+}
+
+impl Sleeper {
+    pub fn new(
+        backend: SleepBackend,
+        clockid: libc::clockid_t,
+        spin_margin_ns: u64,
+    ) -> Result<Sleeper, RtError> {
+        //! Prepare the backend before the measurement starts
+        let state = match backend {
+            SleepBackend::Condvar => State::Condvar(Mutex::new(()), Condvar::new()),
+            SleepBackend::Futex => State::Futex(AtomicU32::new(0)),
+            SleepBackend::Epoll => {
+                let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+                if fd < 0 {
+                    return Err(RtError::last("epoll_create1"));
+                }
+                State::Epoll(unsafe { OwnedFd::from_raw_fd(fd) })
+            }
+            _ => State::None,
+        };
+        let sleeper = Sleeper {
+            backend,
+            clockid,
+            spin_margin_ns,
+            state,
+        };
+        if backend == SleepBackend::Epoll && sleeper.epoll_pwait2(0) < 0 {
+            // Probe with a zero timeout, older kernels have no epoll_pwait2
+            return Err(RtError::last("epoll_pwait2"));
+        }
+        Ok(sleeper)
+    }
+
+    fn epoll_pwait2(&self, sleep_ns: u64) -> libc::c_long {
+        let State::Epoll(epfd) = &self.state else {
+            return -1;
+        };
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        let timeout = to_timespec(sleep_ns);
+        let sigmask: *const libc::sigset_t = std::ptr::null();
+        unsafe {
+            libc::syscall(
+                libc::SYS_epoll_pwait2,
+                epfd.as_raw_fd(),
+                &mut event,
+                1,
+                &timeout,
+                sigmask,
+                0,
+            )
+        }
+    }
+
+    pub fn sleep(&mut self, sleep_ns: u64) -> Option<Wakeup> {
+        //! Sleep for a relative interval
+        //!
+        //! Backends that may wake up early (spurious wakeups, unpark tokens)
+        //! sleep again for the rest of the interval.
+        if self.backend == SleepBackend::Spin {
+            let deadline = clock_gettime(self.clockid).add_ns(sleep_ns);
+            return self.sleep_until(&deadline);
+        }
+        let timeout = to_timespec(sleep_ns);
+        let duration = Duration::from_nanos(sleep_ns);
+        let ret = match (&self.backend, &self.state) {
+            (SleepBackend::ClockNanosleep, _) => {
+                sleep_clock_nanosleep(self.clockid, sleep_ns);
+                0
+            }
+            (SleepBackend::ThreadSleep, _) => {
+                thread::sleep(duration);
+                0
+            }
+            (SleepBackend::ParkTimeout, _) => {
+                let deadline = Instant::now() + duration;
+                let mut now = Instant::now();
+                while now < deadline {
+                    thread::park_timeout(deadline - now);
+                    now = Instant::now();
+                }
+                0
+            }
+            (SleepBackend::Condvar, State::Condvar(mutex, condvar)) => {
+                let deadline = Instant::now() + duration;
+                let mut guard = mutex.lock().unwrap();
+                let mut now = Instant::now();
+                while now < deadline {
+                    guard = condvar.wait_timeout(guard, deadline - now).unwrap().0;
+                    now = Instant::now();
+                }
+                0
+            }
+            (SleepBackend::Poll, _) => {
+                let sigmask: *const libc::sigset_t = std::ptr::null();
+                unsafe { libc::ppoll(std::ptr::null_mut(), 0, &timeout, sigmask) }
+            }
+            (SleepBackend::Epoll, _) => self.epoll_pwait2(sleep_ns) as libc::c_int,
+            (SleepBackend::Futex, State::Futex(word)) => {
+                let op = libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG;
+                let ret =
+                    unsafe { libc::syscall(libc::SYS_futex, word.as_ptr(), op, 0u32, &timeout) };
+                // Timing out is the expected outcome here
+                match (ret, errno().0) {
+                    (-1, libc::ETIMEDOUT) => 0,
+                    (ret, _) => ret as libc::c_int,
+                }
+            }
+            (SleepBackend::Select, _) => {
+                let sigmask: *const libc::sigset_t = std::ptr::null();
+                unsafe {
+                    libc::pselect(
+                        0,
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                        &timeout,
+                        sigmask,
+                    )
+                }
+            }
+            _ => unreachable!("sleeper state does not match {:?}", self.backend),
+        };
+        if ret != 0 {
+            verbose!("{} fails", self.backend.name());
+        }
+        None
+    }
+
+    pub fn sleep_until(&mut self, deadline: &Timespec) -> Option<Wakeup> {
+        //! Sleep until an absolute deadline of the clock
+        //!
+        //! Only clock_nanosleep supports this natively, the others sleep for
+        //! the remaining time like an event loop computing its timeout would.
+        //! Returns the wakeup of the sleep part for SleepBackend::Spin.
+        match self.backend {
+            SleepBackend::ClockNanosleep => sleep_clock_nanosleep_abs(self.clockid, deadline),
+            SleepBackend::Spin => return self.spin_until(deadline),
+            _ => {
+                let now = clock_gettime(self.clockid);
+                let remaining = Timespec::diff_ns(now, *deadline);
+                if remaining > 0 {
+                    self.sleep(remaining as u64);
+                }
+            }
+        }
+        None
+    }
+
+    fn spin_until(&self, deadline: &Timespec) -> Option<Wakeup> {
+        //! Sleep until deadline - spin margin and spin for the rest
+        //!
+        //! There is no sleep part if we are already within the margin.
+        let target = deadline.sub_ns(self.spin_margin_ns);
+        let mut wakeup = None;
+        if Timespec::diff_ns(clock_gettime(self.clockid), target) > 0 {
+            sleep_clock_nanosleep_abs(self.clockid, &target);
+            let now = clock_gettime(self.clockid);
+            wakeup = Some(Wakeup {
+                latency_ns: Timespec::diff_ns(target, now).max(0) as u64,
+                late: Timespec::diff_ns(*deadline, now) > 0,
+            });
+        }
+        while Timespec::diff_ns(clock_gettime(self.clockid), *deadline) > 0 {
+            std::hint::spin_loop();
+        }
+        wakeup
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::ValueEnum;
+    use std::error::Error;
+
+    #[test]
+    fn test_sleepers() -> Result<(), Box<dyn Error>> {
+        let clockid = libc::CLOCK_MONOTONIC;
+        for backend in SleepBackend::value_variants() {
+            let mut sleeper = Sleeper::new(*backend, clockid, 50_000)?;
+            let begin = clock_gettime(clockid);
+            sleeper.sleep(200_000);
+            let end = clock_gettime(clockid);
+            assert!(
+                Timespec::diff_ns(begin, end) >= 200_000,
+                "{}",
+                backend.name()
+            );
+
+            let deadline = clock_gettime(clockid).add_ns(200_000);
+            sleeper.sleep_until(&deadline);
+            let end = clock_gettime(clockid);
+            assert!(Timespec::diff_ns(deadline, end) >= 0, "{}", backend.name());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_spin_wakeup() -> Result<(), Box<dyn Error>> {
+        let clockid = libc::CLOCK_MONOTONIC;
+        let mut sleeper = Sleeper::new(SleepBackend::Spin, clockid, 100_000)?;
+        let deadline = clock_gettime(clockid).add_ns(1_000_000);
+        assert!(sleeper.sleep_until(&deadline).is_some());
+        assert!(Timespec::diff_ns(deadline, clock_gettime(clockid)) >= 0);
+
+        // Within the margin there is only spinning
+        let deadline = clock_gettime(clockid).add_ns(10_000);
+        assert_eq!(sleeper.sleep_until(&deadline), None);
+        Ok(())
+    }
+}
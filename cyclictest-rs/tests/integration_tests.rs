@@ -3,10 +3,56 @@ mod test {
 
     use std::error::Error;
 
+    use cyclictest_rs::{MeasurementConfig, MeasurementType, Policy};
+
     #[test]
     pub fn test_int() -> Result<(), Box<dyn Error>> {
         cyclictest_rs::cyclictest_main()?;
         assert_eq!(1, 1);
         Ok(())
     }
+
+    #[test]
+    pub fn test_run_report() -> Result<(), Box<dyn Error>> {
+        let config = MeasurementConfig::new(MeasurementType::ClockNanosleepGettime)
+            .threads(2)
+            .interval_us(200)
+            .distance_us(100)
+            .loops(100)
+            .policy(Policy::Other)
+            .priority(0)
+            // Runs without sudo as well
+            .allow_degraded(true);
+        let report = cyclictest_rs::run(&config)?;
+        assert_eq!(report.threads.len(), 2);
+        for thread in &report.threads {
+            assert_eq!(thread.cycles, 100);
+            let samples = thread.hist.iter().sum::<u32>() + thread.overflows;
+            assert_eq!(samples, 100);
+            assert!(thread.min <= thread.average() && thread.average() <= thread.max);
+        }
+        assert_eq!(report.breaktrace, None);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_run_record_samples() -> Result<(), Box<dyn Error>> {
+        let config = MeasurementConfig::new(MeasurementType::ClockNanosleepGettime)
+            .threads(1)
+            .interval_us(200)
+            .loops(100)
+            .policy(Policy::Other)
+            .priority(0)
+            .allow_degraded(true)
+            .record_samples(60);
+        let report = cyclictest_rs::run(&config)?;
+        let samples = &report.samples[0];
+        // The last 60 cycles
+        let cycles: Vec<u64> = samples.iter().map(|s| s.cycle).collect();
+        assert_eq!(cycles, (41..=100).collect::<Vec<u64>>());
+        assert!(samples.windows(2).all(|w| w[0].wakeup_ns < w[1].wakeup_ns));
+        let max = samples.iter().map(|s| s.latency_ns).max().unwrap();
+        assert!(max <= report.threads[0].max);
+        Ok(())
+    }
 }